@@ -11,55 +11,379 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::Write;
-use std::net::TcpStream;
-use std::time::Duration;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::cmp;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
-use std::collections::HashSet;
-use util::codec::rpc;
-use util::make_std_tcp_conn;
+use std::collections::{HashMap, HashSet};
+use util::codec::{self, rpc};
+use util::duration_to_ms;
+use util::sockopt::{self, SocketOpt};
 
 use rand::{self, Rng};
 
+use kvproto::metapb;
 use kvproto::pdpb::{self, Request, Response};
 use kvproto::msgpb::{Message, MessageType};
 
-use super::{Result, protocol};
+use super::{Error, PdClient, Result, protocol};
+use super::region_cache::RegionCache;
 use super::metrics::*;
 
-const MAX_PD_SEND_RETRY_COUNT: usize = 100;
-const SOCKET_READ_TIMEOUT: u64 = 3;
-const SOCKET_WRITE_TIMEOUT: u64 = 3;
+const DEFAULT_MAX_RETRY_COUNT: usize = 100;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 3;
+const BACKOFF_BASE_MS: u64 = 100;
+const BACKOFF_MAX_MS: u64 = 3000;
+const DEFAULT_MEMBERS_REFRESH_INTERVAL_SECS: u64 = 60;
+const DEFAULT_KEEPALIVE_TIME_SECS: u64 = 10;
+const DEFAULT_KEEPALIVE_TIMEOUT_SECS: u64 = 3;
+const DEFAULT_LEADER_REFRESH_THRESHOLD: usize = 3;
+const DEFAULT_CONNECTION_POOL_SIZE: usize = 1;
+const DEFAULT_CLUSTER_CONFIG_TTL_SECS: u64 = 10;
+const DEFAULT_SINGLE_PD_RETRY_COUNT: usize = 10;
+// The backoff sleep is split into slices of this length to notice `close`.
+const CLOSE_CHECK_INTERVAL_MS: u64 = 50;
 
 const PD_RPC_PREFIX: &'static str = "/pd/rpc";
+// A proxy answering CONNECT with a longer header is treated as broken.
+const MAX_PROXY_RESP_HEADER_LEN: usize = 8192;
 
 // Only for `validate_endpoints`.
 const VALIDATE_MSG_ID: u64 = 0;
 const VALIDATE_CLUSTER_ID: u64 = 0;
+// At most so many nodes are probed at the same time by `validate_endpoints`.
+const MAX_PROBE_THREADS: usize = 8;
+
+// Returns a random duration in [0.5, 1.5] * `ms`, so that many clients
+// don't reconnect to PD in lockstep.
+fn jitter_ms(ms: u64) -> Duration {
+    let half = ms / 2;
+    Duration::from_millis(half + rand::thread_rng().gen_range(0, ms + 1))
+}
+
+// Exponential backoff with random jitter.
+#[derive(Debug)]
+pub struct Backoff {
+    base_ms: u64,
+    max_ms: u64,
+    current_ms: u64,
+}
+
+impl Backoff {
+    pub fn new(base_ms: u64, max_ms: u64) -> Backoff {
+        Backoff {
+            base_ms: base_ms,
+            max_ms: max_ms,
+            current_ms: base_ms,
+        }
+    }
+
+    // Returns a delay in [current / 2, current * 3 / 2] but no more than max,
+    // and doubles current up to max.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current_ms;
+        self.current_ms = cmp::min(self.current_ms * 2, self.max_ms);
+        cmp::min(jitter_ms(delay), Duration::from_millis(self.max_ms))
+    }
+
+    fn reset(&mut self) {
+        self.current_ms = self.base_ms;
+    }
+}
+
+/// `Connector` opens the connection to a PD endpoint which requests are sent on.
+pub type Connector = Fn(&str, Duration) -> Result<TcpStream> + Send + Sync;
+
+// Wraps the connector so that `RpcClientCore` can still derive `Debug`.
+#[derive(Clone)]
+struct ConnectFn(Arc<Connector>);
+
+impl fmt::Debug for ConnectFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConnectFn")
+    }
+}
+
+/// `ReconnectPolicy` decides when a request which times out is retried on a new connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReconnectPolicy {
+    /// Reconnect on every failure.
+    Always,
+    /// Retry on the same connection after a timeout, and reconnect after this many
+    /// timeouts in a row. Other transport errors always reconnect, because the
+    /// connection is broken.
+    AfterTimeouts(usize),
+}
+
+/// `EndpointStrategy` decides the order in which the PD nodes are tried, both when
+/// validating the endpoints and when connecting to a node other than the leader.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EndpointStrategy {
+    /// The configured endpoints in the given order, then the other members, so a
+    /// local endpoint can be preferred by listing it first.
+    InOrder,
+    /// A random order, so that clients spread over the nodes.
+    Shuffle,
+    /// From the fastest node to the slowest one, nodes whose latency is unknown are
+    /// the last and nodes with the same latency are in random order.
+    LowLatency,
+}
+
+// Orders `items` by `strategy`, `latency` is only asked for by `LowLatency`,
+// and None means the latency is unknown.
+fn order_by_strategy<T, F, R>(items: &mut [T], strategy: EndpointStrategy, latency: F, rng: &mut R)
+    where F: Fn(&T) -> Option<Duration>,
+          R: Rng
+{
+    match strategy {
+        EndpointStrategy::InOrder => {}
+        EndpointStrategy::Shuffle => rng.shuffle(items),
+        EndpointStrategy::LowLatency => {
+            rng.shuffle(items);
+            // The sort is stable, so the shuffle still breaks ties.
+            items.sort_by_key(|item| match latency(item) {
+                Some(latency) => (0, latency),
+                None => (1, Duration::from_secs(0)),
+            });
+        }
+    }
+}
+
+/// `OverloadPolicy` decides what a request does when the limit set by
+/// `RpcClientBuilder::max_inflight_requests` is reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverloadPolicy {
+    /// Wait until another request finishes, or the deadline of the call is reached.
+    Queue,
+    /// Return `TooManyRequests` at once.
+    FailFast,
+}
+
+// Counts the in-flight requests of a client, shared by all its clones.
+#[derive(Debug)]
+struct InflightLimit {
+    limit: usize,
+    policy: OverloadPolicy,
+    count: Mutex<usize>,
+    cond: Condvar,
+}
+
+// Releases the slot of a request when it finishes.
+struct InflightGuard<'a> {
+    limit: &'a InflightLimit,
+}
+
+impl InflightLimit {
+    fn acquire(&self) -> Result<InflightGuard> {
+        let mut count = self.count.lock().unwrap();
+        while *count >= self.limit {
+            if self.policy == OverloadPolicy::FailFast {
+                PD_REQUEST_FAILURE_COUNTER_VEC.with_label_values(&["overload"]).inc();
+                return Err(Error::TooManyRequests(self.limit));
+            }
+            if protocol::is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let mut wait = Duration::from_millis(CLOSE_CHECK_INTERVAL_MS);
+            if let Some(deadline) = protocol::current_deadline() {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::Timeout(format!("waited for {} in-flight requests",
+                                                      self.limit)));
+                }
+                wait = cmp::min(wait, deadline - now);
+            }
+            count = self.cond.wait_timeout(count, wait).unwrap().0;
+        }
+        *count += 1;
+        Ok(InflightGuard { limit: self })
+    }
+}
+
+impl<'a> Drop for InflightGuard<'a> {
+    fn drop(&mut self) {
+        *self.limit.count.lock().unwrap() -= 1;
+        self.limit.cond.notify_one();
+    }
+}
+
+// The hook set by `RpcClientBuilder::on_leader_change`.
+#[derive(Clone)]
+struct LeaderChangeFn(Arc<Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for LeaderChangeFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LeaderChangeFn")
+    }
+}
+
+// Keeps the leader a core connects to until the core is unlocked, then reports it
+// to the hook, so the hook may use the client without a deadlock.
+#[derive(Clone, Debug, Default)]
+struct LeaderNotifier {
+    hook: Option<LeaderChangeFn>,
+    pending: Arc<Mutex<Option<String>>>,
+}
+
+impl LeaderNotifier {
+    fn set(&self, leader: String) {
+        if self.hook.is_some() {
+            *self.pending.lock().unwrap() = Some(leader);
+        }
+    }
+
+    // Must be called without any core locked.
+    fn notify(&self) {
+        let leader = self.pending.lock().unwrap().take();
+        if let (Some(hook), Some(leader)) = (self.hook.as_ref(), leader) {
+            (hook.0)(&leader);
+        }
+    }
+}
+
+// Shared by all cores of a client to find a reachable PD node once for all of them.
+#[derive(Debug, Default)]
+struct ReconnectState {
+    // Increases every time a core finds a reachable node.
+    generation: u64,
+    endpoint: String,
+    // A core is looking for a reachable node, the others wait for it.
+    searching: bool,
+    // How long connecting to or probing an endpoint takes last time.
+    latencies: HashMap<String, Duration>,
+}
+
+// The cluster config kept by `cluster_config_cached`.
+#[derive(Debug, Default)]
+struct ClusterConfigCache {
+    // The config and the time it is fetched.
+    config: Option<(metapb::Cluster, Instant)>,
+    // Increases every time the config is invalidated, so a fetch which starts before
+    // doesn't bring the old config back.
+    version: u64,
+}
 
 #[derive(Debug)]
 struct RpcClientCore {
     endpoints: Vec<String>,
+    cluster_id: u64,
+    members: pdpb::GetPDMembersResponse,
+    members_refresh_time: Instant,
+    members_refresh_interval: Duration,
     stream: Option<TcpStream>,
+    // The endpoint which `stream` connects to.
+    endpoint: String,
+    connect: ConnectFn,
+    // The HTTP proxy used to reload members, see `RpcClientBuilder::proxy`.
+    proxy: Option<String>,
+    // Read and write timeout of every request, a timed out request is retried.
+    request_timeout: Duration,
+    max_retry_count: usize,
+    // A request stops retrying after this long even if `max_retry_count` is not reached.
+    retry_deadline: Option<Duration>,
+    backoff: Backoff,
+    // Reloads the leader after this many consecutive failures of a request, 0 disables it.
+    leader_refresh_threshold: usize,
+    // TCP keepalive of `stream`, see `RpcClientBuilder::keepalive`.
+    keepalive_time: Option<Duration>,
+    keepalive_timeout: Duration,
+    // Shared with `RpcClient`, so `close` doesn't need the lock held by a pending request.
+    closed: Arc<AtomicBool>,
+    // The cluster ID PD answers with after it is re-bootstrapped, shared by all cores.
+    // Once it is set, all requests fail.
+    changed_cluster_id: Arc<Mutex<Option<u64>>>,
+    reconnect: Arc<Mutex<ReconnectState>>,
+    // Notified when a core stops looking for a reachable node.
+    reconnected: Arc<Condvar>,
+    // The generation of `reconnect` when this core connects last time.
+    generation: u64,
+    // Shared with `RpcClient`, so ids increase on every connection.
+    msg_id: Arc<AtomicUsize>,
+    // When the last request is sent, see `RpcClientBuilder::idle_ping_interval`.
+    last_send_time: Instant,
+    // The order of the nodes after the leader, see `RpcClientBuilder::endpoint_strategy`.
+    strategy: EndpointStrategy,
+    // See `RpcClientBuilder::single_pd_retry_count`.
+    single_pd_retry_count: usize,
+    // Prefixed to every log line, see `RpcClientBuilder::log_context`.
+    tag: String,
+    leader_notifier: LeaderNotifier,
+}
+
+fn send_msg(stream: &mut TcpStream,
+            label: &str,
+            msg_id: u64,
+            message: &Request,
+            timeout: Duration)
+            -> Result<(u64, Response)> {
+    send_msg_at_boundary(stream, label, msg_id, message, timeout, &mut false)
 }
 
-fn send_msg(stream: &mut TcpStream, msg_id: u64, message: &Request) -> Result<(u64, Response)> {
-    let timer = PD_SEND_MSG_HISTOGRAM.start_timer();
+// Counts the bytes which go through the stream, so that after a failure we can
+// tell whether the stream stops between two frames.
+struct CountedStream<'a> {
+    stream: &'a mut TcpStream,
+    written: usize,
+    read: usize,
+    // `read` when the last complete frame is decoded.
+    frame_end: usize,
+}
+
+impl<'a> Read for CountedStream<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.stream.read(buf));
+        self.read += n;
+        Ok(n)
+    }
+}
+
+impl<'a> Write for CountedStream<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.stream.write(buf));
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Like `send_msg`, and if it fails, `at_boundary` tells whether the request is
+// written completely or not at all, and no byte of an unfinished response is read.
+// Only then the stream can carry the next request, because a timeout may land in
+// the middle of a frame, and the rest of the frame would be taken as a header.
+fn send_msg_at_boundary(stream: &mut TcpStream,
+                        label: &str,
+                        msg_id: u64,
+                        message: &Request,
+                        timeout: Duration,
+                        at_boundary: &mut bool)
+                        -> Result<(u64, Response)> {
+    let timer = PD_SEND_MSG_HISTOGRAM_VEC.with_label_values(&[label]).start_timer();
 
     let mut req = Message::new();
 
     req.set_msg_type(MessageType::PdReq);
     // TODO: optimize clone later in HTTP refactor.
     req.set_pd_req(message.clone());
+    let mut frame = vec![];
+    try!(rpc::encode_msg(&mut frame, msg_id, &req));
 
-    try!(stream.set_write_timeout(Some(Duration::from_secs(SOCKET_WRITE_TIMEOUT))));
-    try!(rpc::encode_msg(stream, msg_id, &req));
-
-    try!(stream.set_read_timeout(Some(Duration::from_secs(SOCKET_READ_TIMEOUT))));
-    let mut resp = Message::new();
-    let id = try!(rpc::decode_msg(stream, &mut resp));
+    let mut stream = CountedStream {
+        stream: stream,
+        written: 0,
+        read: 0,
+        frame_end: 0,
+    };
+    let res = exchange_msg(&mut stream, &frame, msg_id, timeout);
+    *at_boundary = (stream.written == 0 || stream.written == frame.len()) &&
+                   stream.read == stream.frame_end;
+    let (id, mut resp) = try!(res);
     if resp.get_msg_type() != MessageType::PdResp {
         return Err(box_err!("invalid pd response type {:?}", resp.get_msg_type()));
     }
@@ -68,9 +392,189 @@ fn send_msg(stream: &mut TcpStream, msg_id: u64, message: &Request) -> Result<(u
     Ok((id, resp.take_pd_resp()))
 }
 
-fn rpc_connect(endpoint: &str) -> Result<TcpStream> {
-    let mut stream = try!(make_std_tcp_conn(endpoint));
-    try!(stream.set_write_timeout(Some(Duration::from_secs(SOCKET_WRITE_TIMEOUT))));
+fn exchange_msg(stream: &mut CountedStream,
+                frame: &[u8],
+                msg_id: u64,
+                timeout: Duration)
+                -> Result<(u64, Message)> {
+    try!(stream.stream.set_write_timeout(Some(timeout)));
+    try!(stream.write_all(frame));
+
+    try!(stream.stream.set_read_timeout(Some(timeout)));
+    loop {
+        let mut resp = Message::new();
+        let id = try!(rpc::decode_msg(stream, &mut resp));
+        stream.frame_end = stream.read;
+        if id >= msg_id {
+            return Ok((id, resp));
+        }
+        // The response to an earlier request which timed out may arrive late
+        // on a reused connection, skip it.
+        warn!("skip the stale PD response {}, want {}", id, msg_id);
+    }
+}
+
+// Writes all the requests before reading any response, PD answers the requests
+// on a connection in order, so it saves a round trip for every request but the first.
+fn pipeline_msgs(stream: &mut TcpStream,
+                 label: &str,
+                 msg_ids: &[u64],
+                 messages: &[Request],
+                 timeout: Duration)
+                 -> Result<Vec<Response>> {
+    let timer = PD_SEND_MSG_HISTOGRAM_VEC.with_label_values(&[label]).start_timer();
+
+    try!(stream.set_write_timeout(Some(timeout)));
+    for (&msg_id, message) in msg_ids.iter().zip(messages) {
+        let mut req = Message::new();
+        req.set_msg_type(MessageType::PdReq);
+        req.set_pd_req(message.clone());
+        try!(rpc::encode_msg(stream, msg_id, &req));
+    }
+
+    try!(stream.set_read_timeout(Some(timeout)));
+    let mut resps = Vec::with_capacity(msg_ids.len());
+    for &msg_id in msg_ids {
+        let mut resp = Message::new();
+        let mut id = try!(rpc::decode_msg(stream, &mut resp));
+        while id < msg_id {
+            warn!("skip the stale PD response {}, want {}", id, msg_id);
+            resp = Message::new();
+            id = try!(rpc::decode_msg(stream, &mut resp));
+        }
+        if id != msg_id {
+            return Err(box_err!("pd response msg_id not match, want {}, got {}", msg_id, id));
+        }
+        if resp.get_msg_type() != MessageType::PdResp {
+            return Err(box_err!("invalid pd response type {:?}", resp.get_msg_type()));
+        }
+        resps.push(resp.take_pd_resp());
+    }
+    timer.observe_duration();
+
+    Ok(resps)
+}
+
+// Returns a bounded label for the failure of sending a message to PD.
+fn send_err_label(e: &Error) -> &'static str {
+    let kind = match *e {
+        Error::Io(ref e) |
+        Error::Codec(codec::Error::Io(ref e)) => e.kind(),
+        _ => return "rpc_error",
+    };
+    match kind {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => "timeout",
+        _ => "rpc_error",
+    }
+}
+
+// Parses an endpoint in the form of `host:port` or `[ipv6]:port`.
+fn parse_endpoint(endpoint: &str) -> Result<(&str, u16)> {
+    let (host, port) = if endpoint.starts_with('[') {
+        match endpoint.find("]:") {
+            Some(pos) => (&endpoint[1..pos], &endpoint[pos + 2..]),
+            None => return Err(box_err!("invalid ipv6 endpoint {}", endpoint)),
+        }
+    } else {
+        match endpoint.rfind(':') {
+            // An IPv6 literal must be bracketed, otherwise we can't tell the port apart.
+            Some(pos) if !endpoint[..pos].contains(':') => {
+                (&endpoint[..pos], &endpoint[pos + 1..])
+            }
+            Some(_) => return Err(box_err!("ipv6 endpoint {} must be bracketed", endpoint)),
+            None => return Err(box_err!("endpoint {} has no port", endpoint)),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(box_err!("endpoint {} has no host", endpoint));
+    }
+
+    match port.parse::<u16>() {
+        Ok(port) => Ok((host, port)),
+        Err(e) => Err(box_err!("invalid port in endpoint {}: {:?}", endpoint, e)),
+    }
+}
+
+/// `rpc_connect` connects to the PD endpoint and asks PD to serve RPC on it,
+/// it is the default `Connector`.
+pub fn rpc_connect(endpoint: &str, timeout: Duration) -> Result<TcpStream> {
+    proxy_connect(endpoint, None, timeout)
+}
+
+// Opens a tunnel to `endpoint` through the HTTP proxy with the CONNECT method.
+fn http_tunnel(proxy: &str, endpoint: &str, timeout: Duration) -> Result<TcpStream> {
+    let (host, port) = try!(parse_endpoint(proxy));
+    let addrs: Vec<_> = try!((host, port).to_socket_addrs()).collect();
+    let mut stream = try!(connect_addrs(proxy, &addrs, timeout));
+    try!(stream.set_write_timeout(Some(timeout)));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", endpoint)
+        .as_bytes()));
+
+    // Read the header byte by byte, so nothing sent by PD later is consumed.
+    let mut header = vec![];
+    let mut b = [0; 1];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_PROXY_RESP_HEADER_LEN {
+            return Err(box_err!("proxy {} response header is too long", proxy));
+        }
+        try!(stream.read_exact(&mut b));
+        header.push(b[0]);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let status_line = header.lines().next().unwrap_or("");
+    // Like `HTTP/1.1 200 Connection established`.
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(stream),
+        _ => {
+            Err(box_err!("proxy {} refuses to connect to {}: {}",
+                         proxy,
+                         endpoint,
+                         status_line))
+        }
+    }
+}
+
+// Connects without blocking, so that connecting to a blackholed address fails after
+// `timeout` rather than the SYN timeout of the kernel.
+fn connect_timeout(addr: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    match sockopt::connect_timeout(&addr, timeout) {
+        Ok(stream) => Ok(stream),
+        Err(ref e) if e.kind() == ErrorKind::TimedOut => Err(Error::Timeout(format!("{}", e))),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+// Connects to the first reachable address of the endpoint. A host name, like a
+// headless service in Kubernetes, may resolve to all PD nodes while some are down.
+// Every address may take up to `timeout`.
+fn connect_addrs(endpoint: &str, addrs: &[SocketAddr], timeout: Duration) -> Result<TcpStream> {
+    let mut failures = vec![];
+    for addr in addrs {
+        match connect_timeout(*addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                warn!("failed to connect to {} of {}: {:?}", addr, endpoint, e);
+                failures.push(format!("{}: {}", addr, e));
+            }
+        }
+    }
+    Err(box_err!("no address of {} is reachable: [{}]", endpoint, failures.join(", ")))
+}
+
+// Connects to the PD endpoint directly, or through the HTTP proxy if it is set.
+fn proxy_connect(endpoint: &str, proxy: Option<&str>, timeout: Duration) -> Result<TcpStream> {
+    let _timer = PD_CONNECT_HISTOGRAM.start_timer();
+    let (host, port) = try!(parse_endpoint(endpoint));
+    let mut stream = match proxy {
+        Some(proxy) => try!(http_tunnel(proxy, endpoint, timeout)),
+        None => {
+            let addrs: Vec<_> = try!((host, port).to_socket_addrs()).collect();
+            try!(connect_addrs(endpoint, &addrs, timeout))
+        }
+    };
+    try!(stream.set_write_timeout(Some(timeout)));
 
     // Send a HTTP header to tell PD to hijack this connection for RPC.
     let header_str = format!("GET {} HTTP/1.0\r\n\r\n", PD_RPC_PREFIX);
@@ -81,99 +585,996 @@ fn rpc_connect(endpoint: &str) -> Result<TcpStream> {
     }
 }
 
+// Asks the PD node for its cluster ID and members. PD will not check the
+// cluster ID in the GetPDMembersRequest, so we can send it with any cluster ID.
+fn get_members(endpoint: &str,
+               proxy: Option<&str>,
+               timeout: Duration)
+               -> Result<(u64, pdpb::GetPDMembersResponse)> {
+    let mut stream = try!(proxy_connect(endpoint, proxy, timeout));
+
+    let mut req = protocol::new_request(VALIDATE_CLUSTER_ID, pdpb::CommandType::GetPDMembers);
+    req.set_get_pd_members(pdpb::GetPDMembersRequest::new());
+    let (mid, mut resp) =
+        try!(send_msg(&mut stream, "get_pd_members", VALIDATE_MSG_ID, &req, timeout));
+    if mid != VALIDATE_MSG_ID {
+        return Err(box_err!("PD response msg_id mismatch, want {}, got {}",
+                            VALIDATE_MSG_ID,
+                            mid));
+    }
+
+    Ok((resp.get_header().get_cluster_id(), resp.take_get_pd_members()))
+}
+
+fn leader_endpoints(members: &pdpb::GetPDMembersResponse) -> Vec<String> {
+    members.get_leader()
+        .get_client_urls()
+        .iter()
+        .filter_map(|url| normalize_endpoint(url).ok())
+        .map(|ep| ep.to_owned())
+        .collect()
+}
+
+// Returns the normalized `hint` if it may still be the leader of the cluster.
+fn check_leader_hint(hint: &str,
+                     endpoints: &[String],
+                     members: &pdpb::GetPDMembersResponse)
+                     -> Result<String> {
+    let hint = try!(normalize_endpoint(hint)).to_owned();
+    let leader = leader_endpoints(members);
+    if !leader.is_empty() {
+        if leader.contains(&hint) {
+            return Ok(hint);
+        }
+        return Err(box_err!("PD reports another leader {:?}", leader));
+    }
+    let is_member = members.get_members()
+        .iter()
+        .flat_map(|member| member.get_client_urls())
+        .filter_map(|url| normalize_endpoint(url).ok())
+        .any(|ep| ep == hint);
+    if !is_member && !endpoints.contains(&hint) {
+        return Err(box_err!("it is not a member of the cluster"));
+    }
+    Ok(hint)
+}
+
+// Renders the log prefix of a client, like `[pd cluster_id=1 zone=east]`.
+fn log_tag(cluster_id: u64, context: &[(String, String)]) -> String {
+    let mut tag = format!("[pd cluster_id={}", cluster_id);
+    for &(ref key, ref value) in context {
+        tag.push_str(&format!(" {}={}", key, value));
+    }
+    tag.push(']');
+    tag
+}
+
+// Strips the scheme of an endpoint like `http://127.0.0.1:2379`, because
+// `rpc_connect` only accepts `host:port`. PD member client urls always have
+// a scheme. TLS is not supported, so only `http` is accepted.
+fn normalize_endpoint(endpoint: &str) -> Result<&str> {
+    let pos = match endpoint.find("://") {
+        Some(pos) => pos,
+        None => return Ok(endpoint),
+    };
+    match &endpoint[..pos] {
+        "http" => Ok(&endpoint[pos + 3..]),
+        "https" => Err(box_err!("endpoint {} requires TLS which is not supported", endpoint)),
+        scheme => Err(box_err!("unknown scheme {} in endpoint {}", scheme, endpoint)),
+    }
+}
+
 impl RpcClientCore {
-    fn new(endpoints: Vec<String>) -> RpcClientCore {
+    fn new(endpoints: Vec<String>,
+           cluster_id: u64,
+           members: pdpb::GetPDMembersResponse,
+           request_timeout: Duration,
+           max_retry_count: usize)
+           -> RpcClientCore {
         RpcClientCore {
             endpoints: endpoints,
+            cluster_id: cluster_id,
+            members: members,
+            members_refresh_time: Instant::now(),
+            members_refresh_interval: Duration::from_secs(DEFAULT_MEMBERS_REFRESH_INTERVAL_SECS),
             stream: None,
+            endpoint: String::new(),
+            connect: ConnectFn(Arc::new(rpc_connect)),
+            proxy: None,
+            request_timeout: request_timeout,
+            max_retry_count: max_retry_count,
+            retry_deadline: None,
+            backoff: Backoff::new(BACKOFF_BASE_MS, BACKOFF_MAX_MS),
+            leader_refresh_threshold: DEFAULT_LEADER_REFRESH_THRESHOLD,
+            keepalive_time: Some(Duration::from_secs(DEFAULT_KEEPALIVE_TIME_SECS)),
+            keepalive_timeout: Duration::from_secs(DEFAULT_KEEPALIVE_TIMEOUT_SECS),
+            closed: Arc::new(AtomicBool::new(false)),
+            changed_cluster_id: Arc::new(Mutex::new(None)),
+            reconnect: Arc::new(Mutex::new(ReconnectState::default())),
+            reconnected: Arc::new(Condvar::new()),
+            generation: 0,
+            msg_id: Arc::new(AtomicUsize::new(0)),
+            last_send_time: Instant::now(),
+            strategy: EndpointStrategy::Shuffle,
+            single_pd_retry_count: DEFAULT_SINGLE_PD_RETRY_COUNT,
+            tag: log_tag(cluster_id, &[]),
+            leader_notifier: LeaderNotifier::default(),
         }
     }
 
-    fn try_connect(&mut self) -> Result<()> {
-        // Randomize endpoints.
-        let len = self.endpoints.len();
-        let mut indexes: Vec<usize> = (0..len).collect();
-        rand::thread_rng().shuffle(&mut indexes);
+    fn alloc_msg_id(&self) -> u64 {
+        self.msg_id.fetch_add(1, Ordering::Relaxed) as u64
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
 
-        for i in indexes {
-            let ep = &self.endpoints[i];
-            match rpc_connect(ep.as_str()) {
-                Ok(stream) => {
-                    info!("PD client connects to {}", ep);
-                    self.stream = Some(stream);
+    // Returns the configured endpoints and the client urls of all known PD members.
+    fn candidate_endpoints(&self) -> Vec<String> {
+        let mut eps = self.endpoints.clone();
+        for member in self.members.get_members() {
+            for url in member.get_client_urls() {
+                let ep = match normalize_endpoint(url) {
+                    Ok(ep) => ep,
+                    Err(e) => {
+                        warn!("{} invalid PD member url: {:?}", self.tag, e);
+                        continue;
+                    }
+                };
+                if eps.iter().all(|e| e != ep) {
+                    eps.push(ep.to_owned());
+                }
+            }
+        }
+        eps
+    }
+
+    // Reloads PD members from any reachable PD node of the same cluster,
+    // so that we can follow PD members being added or removed.
+    fn refresh_members(&mut self) -> Result<()> {
+        let eps = self.candidate_endpoints();
+        self.refresh_members_from(eps)
+    }
+
+    fn refresh_members_from(&mut self, eps: Vec<String>) -> Result<()> {
+        self.members_refresh_time = Instant::now();
+        for ep in &eps {
+            match get_members(ep, self.proxy.as_ref().map(|p| p.as_str()), self.request_timeout) {
+                Ok((cluster_id, members)) => {
+                    if cluster_id != self.cluster_id {
+                        warn!("{} PD {} belongs to cluster {}, not {}, skip it",
+                              self.tag,
+                              ep,
+                              cluster_id,
+                              self.cluster_id);
+                        continue;
+                    }
+                    self.members = members;
                     return Ok(());
                 }
+                Err(e) => {
+                    warn!("{} failed to get members from PD {}: {:?}", self.tag, ep, e)
+                }
+            }
+        }
+        Err(box_err!("failed to get members from {:?}", eps))
+    }
 
-                Err(_) => {
-                    error!("failed to connect to {}, try next", ep);
-                    continue;
+    // Reloads the members after a request keeps failing, asking the failing node
+    // last because it may report a stale leader. Then `try_connect` connects to
+    // the reported leader directly instead of walking through the nodes.
+    fn refresh_leader(&mut self) {
+        info!("{} PD {} keeps failing, reload the leader", self.tag, self.endpoint);
+        self.stream = None;
+        let mut eps = self.candidate_endpoints();
+        if let Some(pos) = eps.iter().position(|ep| *ep == self.endpoint) {
+            let ep = eps.remove(pos);
+            eps.push(ep);
+        }
+        match self.refresh_members_from(eps) {
+            Ok(()) => info!("{} PD leader is {:?}", self.tag, self.members.get_leader()),
+            Err(e) => warn!("{} failed to reload the leader: {:?}", self.tag, e),
+        }
+    }
+
+    // Asks the PD node behind `stream`, which is not the leader, for the current
+    // members. Returns true if it reports another leader, then `try_connect`
+    // connects to that leader first.
+    fn follow_leader(&mut self, stream: &mut TcpStream) -> bool {
+        let mut req = protocol::new_request(self.cluster_id, pdpb::CommandType::GetPDMembers);
+        req.set_get_pd_members(pdpb::GetPDMembersRequest::new());
+        let mut resp = match send_msg(stream,
+                                      "get_pd_members",
+                                      VALIDATE_MSG_ID,
+                                      &req,
+                                      self.request_timeout) {
+            Ok((VALIDATE_MSG_ID, resp)) => resp,
+            Ok((id, _)) => {
+                warn!("{} PD response msg_id mismatch, want {}, got {}",
+                      self.tag,
+                      VALIDATE_MSG_ID,
+                      id);
+                return false;
+            }
+            Err(e) => {
+                warn!("{} failed to get members from PD {}: {:?}",
+                      self.tag,
+                      self.endpoint,
+                      e);
+                return false;
+            }
+        };
+        if protocol::check_resp(self.cluster_id, &resp).is_err() {
+            return false;
+        }
+
+        let members = resp.take_get_pd_members();
+        let is_other_leader = members.get_leader()
+            .get_client_urls()
+            .iter()
+            .filter_map(|url| normalize_endpoint(url).ok())
+            .any(|ep| ep != self.endpoint);
+        if !is_other_leader {
+            return false;
+        }
+        info!("{} PD {} is not leader, follow the new leader {:?}",
+              self.tag,
+              self.endpoint,
+              members.get_leader());
+        self.members = members;
+        self.members_refresh_time = Instant::now();
+        true
+    }
+
+    // Connects to the leader, or any PD node if the leader fails. `reason` tells
+    // why the old connection is replaced. Only one core looks for a reachable node
+    // at a time, the others wait and connect to the node it finds first. The shared
+    // state is never locked while connecting. Returns the endpoint of the leader if
+    // it connects to a new one.
+    fn try_connect(&mut self, reason: &str) -> Result<Option<String>> {
+        let (generation, found) = {
+            // Don't wait for a core which takes too long.
+            let deadline = Instant::now() + self.request_timeout;
+            let mut state = self.reconnect.lock().unwrap();
+            while state.searching && state.generation == self.generation {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
                 }
+                state = self.reconnected.wait_timeout(state, deadline - now).unwrap().0;
             }
+            // Skip the node if it is what just failed, this core knows better, for
+            // example a new leader reported by that node.
+            let found = if state.generation != self.generation && state.endpoint != self.endpoint {
+                state.endpoint.clone()
+            } else {
+                String::new()
+            };
+            if found.is_empty() {
+                state.searching = true;
+            }
+            (state.generation, found)
+        };
+        if !found.is_empty() {
+            if self.connect_endpoint(found, reason) {
+                self.generation = generation;
+                return Ok(None);
+            }
+            self.reconnect.lock().unwrap().searching = true;
         }
 
-        Err(box_err!("failed to connect to {:?}", self.endpoints))
+        let latency = self.connect_any(reason);
+        let changed = {
+            let mut state = self.reconnect.lock().unwrap();
+            state.searching = false;
+            match latency {
+                Some(latency) => {
+                    state.latencies.insert(self.endpoint.clone(), latency);
+                    state.generation += 1;
+                    let changed = state.endpoint != self.endpoint;
+                    state.endpoint = self.endpoint.clone();
+                    self.generation = state.generation;
+                    Some(changed)
+                }
+                None => None,
+            }
+        };
+        self.reconnected.notify_all();
+        match changed {
+            Some(true) if leader_endpoints(&self.members).contains(&self.endpoint) => {
+                Ok(Some(self.endpoint.clone()))
+            }
+            Some(_) => Ok(None),
+            None => Err(box_err!("failed to connect to {:?}", self.candidate_endpoints())),
+        }
+    }
+
+    // Walks through the leader and then the other nodes until one of them is
+    // connected, returns how long connecting to it takes.
+    fn connect_any(&mut self, reason: &str) -> Option<Duration> {
+        if self.members_refresh_time.elapsed() >= self.members_refresh_interval {
+            if let Err(e) = self.refresh_members() {
+                warn!("{} {:?}, connect with the last known members", self.tag, e);
+            }
+        }
+
+        // Try the leader first, because most requests must be served by it.
+        let mut eps = leader_endpoints(&self.members);
+        // Then the others.
+        let mut others: Vec<String> =
+            self.candidate_endpoints().into_iter().filter(|ep| !eps.contains(ep)).collect();
+        {
+            let state = self.reconnect.lock().unwrap();
+            let latencies = &state.latencies;
+            order_by_strategy(&mut others,
+                              self.strategy,
+                              |ep| latencies.get(ep).cloned(),
+                              &mut rand::thread_rng());
+        }
+        eps.extend(others);
+
+        for ep in eps {
+            let start = Instant::now();
+            if self.connect_endpoint(ep, reason) {
+                return Some(start.elapsed());
+            }
+        }
+        None
+    }
+
+    // Returns true if it connects to `ep`.
+    fn connect_endpoint(&mut self, ep: String, reason: &str) -> bool {
+        match (self.connect.0)(&ep, self.request_timeout) {
+            Ok(stream) => {
+                info!("{} PD client connects to {}", self.tag, ep);
+                if let Err(e) = stream.set_keepalive(self.keepalive_time, self.keepalive_timeout) {
+                    warn!("{} failed to set keepalive for {}: {:?}", self.tag, ep, e);
+                }
+                PD_RECONNECT_COUNTER_VEC.with_label_values(&["success"]).inc();
+                if !self.endpoint.is_empty() && self.endpoint != ep {
+                    info!("{} PD client switches connection, old: {}, new: {}, reason: {}",
+                          self.tag,
+                          self.endpoint,
+                          ep,
+                          reason);
+                    PD_LEADER_CHANGE_COUNTER.inc();
+                }
+                self.stream = Some(stream);
+                self.endpoint = ep;
+                true
+            }
+            Err(e) => {
+                error!("{} failed to connect to {}: {:?}, try next", self.tag, ep, e);
+                PD_RECONNECT_COUNTER_VEC.with_label_values(&["failure"]).inc();
+                false
+            }
+        }
+    }
+
+    // Sleeps before the next retry, but never beyond the retry deadline.
+    fn backoff_sleep(&mut self, start: Instant, call_deadline: Option<Instant>) {
+        let mut delay = self.backoff.next_delay();
+        if let Some(deadline) = self.retry_deadline {
+            let elapsed = start.elapsed();
+            if elapsed >= deadline {
+                return;
+            }
+            delay = cmp::min(delay, deadline - elapsed);
+        }
+        if let Some(deadline) = call_deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            delay = cmp::min(delay, deadline - now);
+        }
+        let sleep_start = Instant::now();
+        while !self.is_closed() && !protocol::is_cancelled() {
+            let elapsed = sleep_start.elapsed();
+            if elapsed >= delay {
+                return;
+            }
+            thread::sleep(cmp::min(delay - elapsed,
+                                   Duration::from_millis(CLOSE_CHECK_INTERVAL_MS)));
+        }
+    }
+
+    // Sends the requests in one pipeline on the current connection without any retry,
+    // the caller should fall back to `send` on error.
+    fn send_pipelined(&mut self,
+                      label: &str,
+                      msg_ids: &[u64],
+                      reqs: &[Request],
+                      call_deadline: Option<Instant>)
+                      -> Result<Vec<Response>> {
+        if self.is_closed() {
+            return Err(box_err!("PD client is closed"));
+        }
+        if protocol::is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let mut timeout = self.request_timeout;
+        if let Some(deadline) = call_deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout("deadline exceeded before pipelining".to_owned()));
+            }
+            timeout = cmp::min(timeout, deadline - now);
+        }
+        if self.stream.is_none() {
+            if let Some(leader) = try!(self.try_connect("not connected")) {
+                self.leader_notifier.set(leader);
+            }
+        }
+
+        let mut stream = self.stream.take().unwrap();
+        let resps = try!(pipeline_msgs(&mut stream, label, msg_ids, reqs, timeout));
+        self.stream = Some(stream);
+        Ok(resps)
     }
 
-    fn send(&mut self, msg_id: u64, req: &Request) -> Result<Response> {
+    fn send(&mut self,
+            label: &str,
+            msg_id: u64,
+            req: &Request,
+            policy: ReconnectPolicy,
+            max_attempts: usize,
+            call_deadline: Option<Instant>)
+            -> Result<Response> {
+        let start = Instant::now();
+        self.last_send_time = start;
+        let mut attempts = 0;
+        let mut failures = 0;
+        let mut timeouts = 0;
+        let mut connect_failures = 0;
+        let mut last_err: Option<Error> = None;
+        // Back off before the next attempt rather than after a failure, so the last
+        // failure is returned without waiting.
+        let mut need_backoff = false;
         // If we post failed, we should retry.
-        for _ in 0..MAX_PD_SEND_RETRY_COUNT {
+        for i in 0..max_attempts {
+            if need_backoff {
+                need_backoff = false;
+                self.backoff_sleep(start, call_deadline);
+            }
+            if self.is_closed() {
+                self.stream = None;
+                return Err(box_err!("PD client is closed"));
+            }
+            if protocol::is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            if let Some(got) = *self.changed_cluster_id.lock().unwrap() {
+                self.stream = None;
+                return Err(Error::ClusterIdChanged(self.cluster_id, got));
+            }
+            // The caller's deadline limits every attempt, including the first one.
+            let mut timeout = self.request_timeout;
+            if let Some(deadline) = call_deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    let err = last_err.as_ref().map_or_else(String::new, |e| format!("{}", e));
+                    return Err(Error::Timeout(format!("deadline exceeded after {:?}, last \
+                                                       error: {}",
+                                                      start.elapsed(),
+                                                      err)));
+                }
+                timeout = cmp::min(timeout, deadline - now);
+            }
+            if i > 0 {
+                if self.retry_deadline.map_or(false, |d| start.elapsed() >= d) {
+                    let err = last_err.map_or_else(String::new, |e| format!("{}", e));
+                    return Err(Error::Timeout(format!("retried for {:?}, last error: {}",
+                                                      start.elapsed(),
+                                                      err)));
+                }
+                PD_RETRY_COUNTER_VEC.with_label_values(&[label]).inc();
+            }
+            if self.leader_refresh_threshold > 0 && failures >= self.leader_refresh_threshold {
+                failures = 0;
+                self.refresh_leader();
+            }
+            attempts += 1;
             // If no stream, try connect first.
-            if self.stream.is_none() && self.try_connect().is_err() {
-                // TODO: figure out a better way to do backoff
-                thread::sleep(Duration::from_millis(50));
-                continue;
+            if self.stream.is_none() {
+                let reason = last_err.as_ref()
+                    .map_or_else(|| "not connected".to_owned(), |e| format!("{}", e));
+                let leader = match self.try_connect(&reason) {
+                    Ok(leader) => leader,
+                    Err(e) => {
+                        failures += 1;
+                        connect_failures += 1;
+                        // There is no other node to wait for.
+                        if connect_failures >= self.single_pd_retry_count {
+                            let eps = self.candidate_endpoints();
+                            if eps.len() == 1 {
+                                error!("{} the only PD {} is unreachable: {:?}",
+                                       self.tag,
+                                       eps[0],
+                                       e);
+                                return Err(Error::SinglePdUnavailable(eps[0].clone()));
+                            }
+                        }
+                        last_err = Some(e);
+                        need_backoff = true;
+                        continue;
+                    }
+                };
+                if let Some(leader) = leader {
+                    self.leader_notifier.set(leader);
+                }
             }
 
             let mut stream = self.stream.take().unwrap();
             // We may send message to a not leader pd, retry.
 
-            let (id, resp) = match send_msg(&mut stream, msg_id, req) {
+            // Every attempt has its own id, so the late response to an earlier attempt
+            // on a reused stream is skipped rather than taken as the response.
+            let attempt_id = if i == 0 { msg_id } else { self.alloc_msg_id() };
+            let mut at_boundary = false;
+            let res = send_msg_at_boundary(&mut stream,
+                                           label,
+                                           attempt_id,
+                                           req,
+                                           timeout,
+                                           &mut at_boundary);
+            let (id, resp) = match res {
                 Err(e) => {
-                    warn!("send message to pd failed {:?}", e);
-                    // TODO: figure out a better way to do backoff
-                    thread::sleep(Duration::from_millis(50));
+                    warn!("{} send request {} to pd failed {:?}",
+                          self.tag,
+                          protocol::request_uuid(req),
+                          e);
+                    PD_REQUEST_FAILURE_COUNTER_VEC.with_label_values(&[send_err_label(&e)]).inc();
+                    failures += 1;
+                    if let ReconnectPolicy::AfterTimeouts(count) = policy {
+                        if send_err_label(&e) == "timeout" {
+                            timeouts += 1;
+                            if timeouts < count && at_boundary {
+                                self.stream = Some(stream);
+                            }
+                        }
+                    }
+                    last_err = Some(e);
+                    need_backoff = true;
                     continue;
                 }
                 Ok((id, resp)) => (id, resp),
             };
 
-            if id != msg_id {
-                return Err(box_err!("pd response msg_id not match, want {}, got {}", msg_id, id));
+            if id != attempt_id {
+                return Err(box_err!("pd response msg_id not match, want {}, got {}",
+                                    attempt_id,
+                                    id));
             }
 
-            self.stream = Some(stream);
+            if protocol::is_retriable_resp(&resp) {
+                // Drop the stream to reconnect to another PD node.
+                warn!("{} pd response error {:?} for request {}, reconnect and retry",
+                      self.tag,
+                      resp.get_header().get_error(),
+                      protocol::request_uuid(req));
+                PD_REQUEST_FAILURE_COUNTER_VEC.with_label_values(&["header_error"]).inc();
+                failures += 1;
+                last_err = Some(box_err!("pd response error {:?}", resp.get_header().get_error()));
+                // Reconnect to the new leader at once if the node knows it.
+                need_backoff = !self.follow_leader(&mut stream);
+                continue;
+            }
 
-            return Ok(resp);
+            // A client closed during the request drops the connection here.
+            if !self.is_closed() {
+                self.stream = Some(stream);
+            }
+            self.backoff.reset();
+
+            // Other errors in the header are returned at once, retrying doesn't help.
+            let res = protocol::check_resp(self.cluster_id, &resp);
+            if res.is_err() {
+                PD_REQUEST_FAILURE_COUNTER_VEC.with_label_values(&["header_error"]).inc();
+            }
+            match res {
+                Err(Error::ClusterMismatch(want, got)) => {
+                    // PD has been wiped and bootstrapped again, the data we have doesn't
+                    // belong to the new cluster, so never talk to it again.
+                    error!("{} PD cluster id changes from {} to {}, fail all requests",
+                           self.tag,
+                           want,
+                           got);
+                    *self.changed_cluster_id.lock().unwrap() = Some(got);
+                    self.stream = None;
+                    return Err(Error::ClusterIdChanged(want, got));
+                }
+                Err(e) => return Err(e),
+                Ok(()) => return Ok(resp),
+            }
         }
 
-        Err(box_err!("send message to pd failed"))
+        match last_err {
+            Some(e) => {
+                let msg = format!("send message to pd failed after {} retries: {}", attempts, e);
+                if send_err_label(&e) == "timeout" {
+                    Err(Error::Timeout(msg))
+                } else {
+                    Err(box_err!(msg))
+                }
+            }
+            None => Err(box_err!("send message to pd failed")),
+        }
     }
 }
 
-#[derive(Debug)]
+/// `RpcClient` is a client of the PD cluster which is safe to use from many threads.
+/// Cloning it is cheap, all the clones share the same connections, so a reconnect done
+/// through one of them is seen by the others.
+#[derive(Clone)]
 pub struct RpcClient {
-    msg_id: AtomicUsize,
-    core: Mutex<RpcClientCore>,
+    msg_id: Arc<AtomicUsize>,
+    // Every core owns one connection and reconnects on its own.
+    cores: Arc<Vec<Mutex<RpcClientCore>>>,
+    next_core: Arc<AtomicUsize>,
+    region_cache: Option<Arc<Mutex<RegionCache>>>,
+    cluster_config: Arc<Mutex<ClusterConfigCache>>,
+    cluster_config_ttl: Duration,
+    closed: Arc<AtomicBool>,
+    // See `RpcClientBuilder::log_context`.
+    tag: String,
+    leader_notifier: LeaderNotifier,
+    // See `RpcClientBuilder::endpoint_strategy`.
+    validate_strategy: EndpointStrategy,
+    // See `RpcClientBuilder::max_inflight_requests`.
+    inflight: Option<Arc<InflightLimit>>,
     pub cluster_id: u64,
 }
 
-impl RpcClient {
-    pub fn new(endpoints: &str) -> Result<RpcClient> {
-        let endpoints: Vec<String> = endpoints.split(',')
-            .map(|s| s.trim().to_owned())
+// Only a summary is printed by default, the members of a large cluster would flood the
+// logs, use `{:#?}` to print everything.
+impl fmt::Debug for RpcClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A core is locked while sending, logging the client shouldn't wait for it.
+        let cores: Vec<_> = self.cores.iter().map(|core| core.try_lock().ok()).collect();
+        if f.alternate() {
+            try!(write!(f,
+                        "RpcClient {{ cluster_id: {}, closed: {}, cores: [",
+                        self.cluster_id,
+                        self.closed.load(Ordering::SeqCst)));
+            for core in &cores {
+                match *core {
+                    Some(ref core) => try!(write!(f, "{:#?}, ", **core)),
+                    None => try!(write!(f, "busy, ")),
+                }
+            }
+            return write!(f, "] }}");
+        }
+
+        let connected = cores.iter()
+            .filter(|core| core.as_ref().map_or(false, |c| c.stream.is_some()))
+            .count();
+        let (members, leader) = match cores.iter().filter_map(|core| core.as_ref()).next() {
+            Some(core) => {
+                let leader = core.members.get_leader().get_client_urls().first().cloned();
+                (format!("{}", core.members.get_members().len()),
+                 leader.unwrap_or_else(|| "none".to_owned()))
+            }
+            None => ("unknown".to_owned(), "unknown".to_owned()),
+        };
+        write!(f,
+               "RpcClient {{ cluster_id: {}, members: {}, leader: {}, connected: {}/{}, \
+                closed: {} }}",
+               self.cluster_id,
+               members,
+               leader,
+               connected,
+               cores.len(),
+               self.closed.load(Ordering::SeqCst))
+    }
+}
+
+/// `RpcClientBuilder` builds a `RpcClient`, options which are not set use the defaults.
+pub struct RpcClientBuilder {
+    endpoints: Vec<String>,
+    request_timeout: Duration,
+    max_retry_count: usize,
+    retry_deadline: Option<Duration>,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    leader_refresh_threshold: usize,
+    members_refresh_interval: Duration,
+    keepalive_time: Option<Duration>,
+    keepalive_timeout: Duration,
+    region_cache_capacity: usize,
+    connect: Option<ConnectFn>,
+    proxy: Option<String>,
+    warmup: bool,
+    connection_pool_size: usize,
+    cluster_config_ttl: Duration,
+    idle_ping_interval: Option<Duration>,
+    connect_strategy: EndpointStrategy,
+    validate_strategy: EndpointStrategy,
+    single_pd_retry_count: usize,
+    log_context: Vec<(String, String)>,
+    max_inflight_requests: Option<(usize, OverloadPolicy)>,
+    leader_hint: Option<String>,
+    on_leader_change: Option<LeaderChangeFn>,
+}
+
+impl RpcClientBuilder {
+    pub fn new() -> RpcClientBuilder {
+        RpcClientBuilder {
+            endpoints: vec![],
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            max_retry_count: DEFAULT_MAX_RETRY_COUNT,
+            retry_deadline: None,
+            backoff_base: Duration::from_millis(BACKOFF_BASE_MS),
+            backoff_max: Duration::from_millis(BACKOFF_MAX_MS),
+            leader_refresh_threshold: DEFAULT_LEADER_REFRESH_THRESHOLD,
+            members_refresh_interval: Duration::from_secs(DEFAULT_MEMBERS_REFRESH_INTERVAL_SECS),
+            keepalive_time: Some(Duration::from_secs(DEFAULT_KEEPALIVE_TIME_SECS)),
+            keepalive_timeout: Duration::from_secs(DEFAULT_KEEPALIVE_TIMEOUT_SECS),
+            region_cache_capacity: 0,
+            connect: None,
+            proxy: None,
+            warmup: false,
+            connection_pool_size: DEFAULT_CONNECTION_POOL_SIZE,
+            cluster_config_ttl: Duration::from_secs(DEFAULT_CLUSTER_CONFIG_TTL_SECS),
+            idle_ping_interval: None,
+            connect_strategy: EndpointStrategy::Shuffle,
+            validate_strategy: EndpointStrategy::InOrder,
+            single_pd_retry_count: DEFAULT_SINGLE_PD_RETRY_COUNT,
+            log_context: vec![],
+            max_inflight_requests: None,
+            leader_hint: None,
+            on_leader_change: None,
+        }
+    }
+
+    /// Sets the PD endpoints separated by comma, like `127.0.0.1:2379,127.0.0.1:3379`.
+    pub fn endpoints(self, endpoints: &str) -> RpcClientBuilder {
+        self.endpoint_list(endpoints.split(','))
+    }
+
+    /// Sets the PD endpoints one by one, an endpoint is used as is besides trimming.
+    pub fn endpoint_list<I, S>(mut self, endpoints: I) -> RpcClientBuilder
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        self.endpoints = endpoints.into_iter()
+            .map(|s| s.as_ref().trim().to_owned())
             .filter(|s| !s.is_empty())
             .collect();
+        self
+    }
+
+    /// A request times out after `timeout`, then it is retried on a new connection.
+    /// It also bounds connecting to every address of a node, and probing every node
+    /// when validating the endpoints.
+    pub fn request_timeout(mut self, timeout: Duration) -> RpcClientBuilder {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// A request, or the construction itself, gives up after `count` failed attempts.
+    pub fn max_retry_count(mut self, count: usize) -> RpcClientBuilder {
+        self.max_retry_count = count;
+        self
+    }
+
+    /// A request gives up once it has been retried for `deadline`, no matter how many
+    /// attempts are left, and returns the last error.
+    pub fn retry_deadline(mut self, deadline: Duration) -> RpcClientBuilder {
+        self.retry_deadline = Some(deadline);
+        self
+    }
+
+    /// Failed requests are retried after a delay starting at `base` and doubling up to `max`.
+    pub fn backoff(mut self, base: Duration, max: Duration) -> RpcClientBuilder {
+        self.backoff_base = base;
+        self.backoff_max = max;
+        self
+    }
+
+    /// Reloads the PD leader and connects to it after a request fails `count` times
+    /// in a row, 0 only relies on reconnecting and the leader reported by `not leader`.
+    pub fn leader_refresh_threshold(mut self, count: usize) -> RpcClientBuilder {
+        self.leader_refresh_threshold = count;
+        self
+    }
+
+    /// Sets how often the PD members are reloaded when reconnecting.
+    pub fn members_refresh_interval(mut self, interval: Duration) -> RpcClientBuilder {
+        self.members_refresh_interval = interval;
+        self
+    }
+
+    /// Probes an idle connection after `time` and drops it if PD doesn't answer
+    /// within `timeout`, `None` disables keepalive. A dropped connection fails the
+    /// next request at once, which reconnects and retries it like any other failure,
+    /// instead of waiting for `request_timeout` on a connection which is long gone.
+    pub fn keepalive(mut self, time: Option<Duration>, timeout: Duration) -> RpcClientBuilder {
+        self.keepalive_time = time;
+        self.keepalive_timeout = timeout;
+        self
+    }
+
+    /// Caches up to `capacity` regions returned by `get_region` and `get_region_by_id`,
+    /// 0 disables the cache, which is the default.
+    pub fn region_cache_capacity(mut self, capacity: usize) -> RpcClientBuilder {
+        self.region_cache_capacity = capacity;
+        self
+    }
+
+    /// Replaces `rpc_connect` with `connect` when connecting for requests, so tests
+    /// can script connection failures, or endpoints can be resolved in another way.
+    /// Validating the endpoints and reloading the members still use `rpc_connect`.
+    pub fn connector<F>(mut self, connect: F) -> RpcClientBuilder
+        where F: Fn(&str, Duration) -> Result<TcpStream> + Send + Sync + 'static
+    {
+        self.connect = Some(ConnectFn(Arc::new(connect)));
+        self
+    }
+
+    /// Connects to PD through the HTTP proxy `proxy` in the form of `host:port`, with
+    /// the CONNECT method. TLS is not supported, so the tunnel carries plain text.
+    /// A connector set by `connector` takes precedence for requests, but validating
+    /// the endpoints and reloading the members still go through the proxy.
+    pub fn proxy(mut self, proxy: &str) -> RpcClientBuilder {
+        self.proxy = Some(proxy.trim().to_owned());
+        self
+    }
+
+    /// Connects to the PD leader in `build`, so the first request doesn't pay for
+    /// connecting. A failed warmup is only logged, the first request connects again.
+    /// It is disabled by default, it does nothing if no leader is known.
+    pub fn warmup(mut self, warmup: bool) -> RpcClientBuilder {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Sends requests on `size` connections to PD, so concurrent requests don't wait
+    /// for each other. A request goes to an idle connection if there is one, otherwise
+    /// the connections are used in turn. Each connection reconnects on its own.
+    pub fn connection_pool_size(mut self, size: usize) -> RpcClientBuilder {
+        self.connection_pool_size = cmp::max(size, 1);
+        self
+    }
+
+    /// Sets how long `cluster_config_cached` may return the cluster config without
+    /// fetching it from PD again.
+    pub fn cluster_config_ttl(mut self, ttl: Duration) -> RpcClientBuilder {
+        self.cluster_config_ttl = ttl;
+        self
+    }
+
+    /// Pings PD in a background thread on every connection which sends nothing for
+    /// `interval`, so a connection silently dropped by the network is replaced before
+    /// a real request needs it. It is disabled by default.
+    pub fn idle_ping_interval(mut self, interval: Duration) -> RpcClientBuilder {
+        self.idle_ping_interval = Some(interval);
+        self
+    }
+
+    /// When the leader is unknown or unreachable, tries the other PD nodes from the
+    /// fastest to the slowest one, measured when validating the endpoints and on every
+    /// connect, instead of in random order. `false` keeps the current strategy.
+    #[deprecated(note = "use `endpoint_strategy(EndpointStrategy::LowLatency)` instead")]
+    pub fn prefer_low_latency(self, prefer: bool) -> RpcClientBuilder {
+        if prefer {
+            self.endpoint_strategy(EndpointStrategy::LowLatency)
+        } else {
+            self
+        }
+    }
+
+    /// Sets the order in which the PD nodes are tried when validating the endpoints,
+    /// whose members are taken from the first usable node, and when connecting to a
+    /// node other than the leader. By default the endpoints are validated in order
+    /// and the other nodes are connected in random order.
+    pub fn endpoint_strategy(mut self, strategy: EndpointStrategy) -> RpcClientBuilder {
+        self.connect_strategy = strategy;
+        self.validate_strategy = strategy;
+        self
+    }
+
+    /// If the cluster has only one PD node, a request returns `SinglePdUnavailable`
+    /// after failing to connect to it `count` times, instead of retrying up to
+    /// `max_retry_count` times for a node which may never come back.
+    pub fn single_pd_retry_count(mut self, count: usize) -> RpcClientBuilder {
+        self.single_pd_retry_count = cmp::max(count, 1);
+        self
+    }
+
+    /// Adds a key/value, like `zone=east`, to the prefix of every log line of the
+    /// client, after the cluster id, so that the logs of clients of different clusters
+    /// or subsystems can be told apart.
+    pub fn log_context(mut self, key: &str, value: &str) -> RpcClientBuilder {
+        self.log_context.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Allows at most `limit` requests of the client and its clones to be in flight
+    /// at the same time, the others wait or fail according to `policy`. There is no
+    /// limit by default.
+    pub fn max_inflight_requests(mut self,
+                                 limit: usize,
+                                 policy: OverloadPolicy)
+                                 -> RpcClientBuilder {
+        self.max_inflight_requests = Some((cmp::max(limit, 1), policy));
+        self
+    }
+
+    /// Connects to `leader` first, which is usually the leader persisted by the hook
+    /// of `on_leader_change` before a restart. It is ignored if it is not a member of
+    /// the cluster, or PD reports another leader when validating the endpoints.
+    pub fn leader_hint(mut self, leader: &str) -> RpcClientBuilder {
+        self.leader_hint = Some(leader.to_owned());
+        self
+    }
+
+    /// Calls `hook` with the endpoint of the new leader every time the client connects
+    /// to a leader other than the last one, so the leader can be persisted somewhere
+    /// and passed to `leader_hint` later. It is called after the request which connects
+    /// returns, with no lock of the client held, so it may use the client.
+    pub fn on_leader_change<F>(mut self, hook: F) -> RpcClientBuilder
+        where F: Fn(&str) + Send + Sync + 'static
+    {
+        self.on_leader_change = Some(LeaderChangeFn(Arc::new(hook)));
+        self
+    }
+
+    pub fn build(mut self) -> Result<RpcClient> {
+        if self.endpoints.is_empty() {
+            return Err(box_err!("no PD endpoints"));
+        }
+        // Skip the endpoints which can never be connected, and fail at once
+        // rather than retrying if none is left.
+        let mut endpoints = Vec::with_capacity(self.endpoints.len());
+        let mut malformed = vec![];
+        for ep in &self.endpoints {
+            let res = normalize_endpoint(ep).and_then(|e| parse_endpoint(e).map(|_| e.to_owned()));
+            match res {
+                Ok(e) => endpoints.push(e),
+                Err(e) => {
+                    warn!("malformed PD endpoint {}: {:?}, skip it", ep, e);
+                    malformed.push(ep.clone());
+                }
+            }
+        }
+        if endpoints.is_empty() {
+            return Err(box_err!("all PD endpoints are malformed: {:?}", malformed));
+        }
+        self.endpoints = endpoints;
+        if let Some(ref proxy) = self.proxy {
+            if let Err(e) = parse_endpoint(proxy) {
+                return Err(box_err!("malformed PD proxy {}: {:?}", proxy, e));
+            }
+        }
+        let connect = match (self.connect.take(), self.proxy.clone()) {
+            (Some(connect), _) => connect,
+            (None, Some(proxy)) => {
+                ConnectFn(Arc::new(move |ep: &str, timeout| {
+                    proxy_connect(ep, Some(proxy.as_str()), timeout)
+                }))
+            }
+            (None, None) => ConnectFn(Arc::new(rpc_connect)),
+        };
 
         let mut cluster_id = VALIDATE_CLUSTER_ID;
-        for _ in 0..MAX_PD_SEND_RETRY_COUNT {
-            match Self::validate_endpoints(&endpoints) {
-                Ok(id) => {
+        let mut members = pdpb::GetPDMembersResponse::new();
+        let mut latencies = HashMap::new();
+        for _ in 0..self.max_retry_count {
+            match RpcClient::validate_endpoints_via(&self.endpoints,
+                                                    self.proxy.clone(),
+                                                    self.request_timeout,
+                                                    self.validate_strategy) {
+                Ok((id, resp, probed)) => {
                     cluster_id = id;
+                    members = resp;
+                    latencies = probed;
                     break;
                 }
                 Err(e) => {
                     warn!("failed to get cluster id from pd: {:?}", e);
-                    thread::sleep(Duration::from_secs(1));
+                    thread::sleep(jitter_ms(1000));
                 }
             }
         }
@@ -182,17 +1583,443 @@ impl RpcClient {
             return Err(box_err!("failed to get cluster id from pd"));
         }
 
-        Ok(RpcClient {
-            msg_id: AtomicUsize::new(0),
-            core: Mutex::new(RpcClientCore::new(endpoints)),
+        let tag = log_tag(cluster_id, &self.log_context);
+        let closed = Arc::new(AtomicBool::new(false));
+        let changed_cluster_id = Arc::new(Mutex::new(None));
+        let mut state = ReconnectState { latencies: latencies, ..ReconnectState::default() };
+        if let Some(hint) = self.leader_hint.take() {
+            match check_leader_hint(&hint, &self.endpoints, &members) {
+                Ok(ep) => {
+                    // Every core connects to the endpoint of a newer generation first.
+                    state.endpoint = ep;
+                    state.generation = 1;
+                }
+                Err(e) => info!("{} ignore PD leader hint {}: {}", tag, hint, e),
+            }
+        }
+        let reconnect = Arc::new(Mutex::new(state));
+        let reconnected = Arc::new(Condvar::new());
+        let msg_id = Arc::new(AtomicUsize::new(0));
+        let leader_notifier = LeaderNotifier {
+            hook: self.on_leader_change.take(),
+            pending: Arc::new(Mutex::new(None)),
+        };
+        let mut cores = Vec::with_capacity(self.connection_pool_size);
+        for _ in 0..self.connection_pool_size {
+            let mut core = RpcClientCore::new(self.endpoints.clone(),
+                                              cluster_id,
+                                              members.clone(),
+                                              self.request_timeout,
+                                              self.max_retry_count);
+            core.backoff = Backoff::new(duration_to_ms(self.backoff_base),
+                                        duration_to_ms(self.backoff_max));
+            core.members_refresh_interval = self.members_refresh_interval;
+            core.leader_refresh_threshold = self.leader_refresh_threshold;
+            core.connect = connect.clone();
+            core.proxy = self.proxy.clone();
+            core.retry_deadline = self.retry_deadline;
+            core.keepalive_time = self.keepalive_time;
+            core.keepalive_timeout = self.keepalive_timeout;
+            core.closed = closed.clone();
+            core.changed_cluster_id = changed_cluster_id.clone();
+            core.reconnect = reconnect.clone();
+            core.reconnected = reconnected.clone();
+            core.msg_id = msg_id.clone();
+            core.strategy = self.connect_strategy;
+            core.single_pd_retry_count = self.single_pd_retry_count;
+            core.tag = tag.clone();
+            core.leader_notifier = leader_notifier.clone();
+            if self.warmup && core.members.has_leader() {
+                match core.try_connect("warmup") {
+                    Ok(Some(leader)) => leader_notifier.set(leader),
+                    Ok(None) => {}
+                    Err(e) => warn!("{} failed to warm up PD client: {:?}", tag, e),
+                }
+            }
+            cores.push(Mutex::new(core));
+        }
+
+        let eps = cores[0].lock().unwrap().candidate_endpoints();
+        if eps.len() == 1 {
+            warn!("{} PD cluster has only one node {}, there is no redundancy", tag, eps[0]);
+        }
+
+        let client = RpcClient {
+            msg_id: msg_id,
+            cores: Arc::new(cores),
+            next_core: Arc::new(AtomicUsize::new(0)),
+            region_cache: if self.region_cache_capacity > 0 {
+                Some(Arc::new(Mutex::new(RegionCache::new(self.region_cache_capacity))))
+            } else {
+                None
+            },
+            cluster_config: Arc::new(Mutex::new(ClusterConfigCache::default())),
+            cluster_config_ttl: self.cluster_config_ttl,
+            closed: closed,
+            tag: tag,
+            leader_notifier: leader_notifier,
+            validate_strategy: self.validate_strategy,
+            inflight: self.max_inflight_requests.map(|(limit, policy)| {
+                Arc::new(InflightLimit {
+                    limit: limit,
+                    policy: policy,
+                    count: Mutex::new(0),
+                    cond: Condvar::new(),
+                })
+            }),
             cluster_id: cluster_id,
-        })
+        };
+        client.leader_notifier.notify();
+
+        if let Some(interval) = self.idle_ping_interval {
+            let cores = Arc::downgrade(&client.cores);
+            let msg_id = client.msg_id.clone();
+            let closed = client.closed.clone();
+            let notifier = client.leader_notifier.clone();
+            try!(thread::Builder::new()
+                .name(thd_name!("pd-ping"))
+                .spawn(move || ping_idle_connections(cores, msg_id, closed, notifier, interval)));
+        }
+        Ok(client)
+    }
+}
+
+// Pings PD on the connections which are idle for `interval`, until the client is
+// closed or dropped. A busy connection is skipped, it is checked by the request.
+fn ping_idle_connections(weak_cores: Weak<Vec<Mutex<RpcClientCore>>>,
+                         msg_id: Arc<AtomicUsize>,
+                         closed: Arc<AtomicBool>,
+                         notifier: LeaderNotifier,
+                         interval: Duration) {
+    loop {
+        let sleep_start = Instant::now();
+        loop {
+            if closed.load(Ordering::SeqCst) {
+                return;
+            }
+            let elapsed = sleep_start.elapsed();
+            if elapsed >= interval {
+                break;
+            }
+            thread::sleep(cmp::min(interval - elapsed,
+                                   Duration::from_millis(CLOSE_CHECK_INTERVAL_MS)));
+        }
+
+        let cores = match weak_cores.upgrade() {
+            Some(cores) => cores,
+            None => return,
+        };
+        for core in cores.iter() {
+            let mut core = match core.try_lock() {
+                Ok(core) => core,
+                Err(_) => continue,
+            };
+            if core.last_send_time.elapsed() < interval {
+                continue;
+            }
+            let mut req = protocol::new_request(core.cluster_id, pdpb::CommandType::GetPDMembers);
+            req.set_get_pd_members(pdpb::GetPDMembersRequest::new());
+            let id = msg_id.fetch_add(1, Ordering::Relaxed) as u64;
+            if let Err(e) = core.send("ping", id, &req, ReconnectPolicy::Always, 1, None) {
+                warn!("{} failed to ping idle PD connection: {:?}", core.tag, e);
+            }
+        }
+        notifier.notify();
+    }
+}
+
+impl Default for RpcClientBuilder {
+    fn default() -> RpcClientBuilder {
+        RpcClientBuilder::new()
+    }
+}
+
+impl RpcClient {
+    pub fn new(endpoints: &str) -> Result<RpcClient> {
+        RpcClientBuilder::new().endpoints(endpoints).build()
+    }
+
+    /// `new_from_endpoints` creates a client from a list of endpoints
+    /// instead of a comma separated string.
+    pub fn new_from_endpoints<I, S>(endpoints: I) -> Result<RpcClient>
+        where I: IntoIterator<Item = S>,
+              S: AsRef<str>
+    {
+        RpcClientBuilder::new().endpoint_list(endpoints).build()
+    }
+
+    /// `new_with_timeout` creates a client whose requests time out after `request_timeout`.
+    /// A timed out request is treated as a connection failure and retried on a new
+    /// connection.
+    pub fn new_with_timeout(endpoints: &str, request_timeout: Duration) -> Result<RpcClient> {
+        RpcClientBuilder::new().endpoints(endpoints).request_timeout(request_timeout).build()
+    }
+
+    /// `new_with_options` creates a client which gives up a request, or the construction
+    /// itself, after `max_retry_count` failed attempts.
+    pub fn new_with_options(endpoints: &str,
+                            request_timeout: Duration,
+                            max_retry_count: usize)
+                            -> Result<RpcClient> {
+        RpcClientBuilder::new()
+            .endpoints(endpoints)
+            .request_timeout(request_timeout)
+            .max_retry_count(max_retry_count)
+            .build()
+    }
+
+    /// `send` sends the request to PD, `label` names the request in metrics.
+    /// Transport errors and `not leader` errors are retried, other errors in the
+    /// response header are returned at once.
+    pub fn send(&self, label: &str, req: &Request) -> Result<Response> {
+        self.send_with_policy(label, req, ReconnectPolicy::Always)
+    }
+
+    /// `send_with_policy` is like `send`, but a timed out request is retried
+    /// on the same connection or a new one according to `policy`.
+    pub fn send_with_policy(&self,
+                            label: &str,
+                            req: &Request,
+                            policy: ReconnectPolicy)
+                            -> Result<Response> {
+        let _guard = try!(self.acquire_inflight());
+        let res = {
+            let mut core = self.pick_core();
+            // Allocate the id with the core locked, so ids increase on every connection
+            // and a stale response can be told apart.
+            let msg_id = self.alloc_msg_id();
+            let max_attempts = core.max_retry_count;
+            core.send(label,
+                      msg_id,
+                      req,
+                      policy,
+                      max_attempts,
+                      protocol::current_deadline())
+        };
+        self.leader_notifier.notify();
+        res
+    }
+
+    /// `send_once` makes a single attempt to send the request, connecting first if
+    /// needed, and returns the error of the attempt at once instead of retrying.
+    pub fn send_once(&self, label: &str, req: &Request) -> Result<Response> {
+        let _guard = try!(self.acquire_inflight());
+        let res = {
+            let mut core = self.pick_core();
+            let msg_id = self.alloc_msg_id();
+            core.send(label,
+                      msg_id,
+                      req,
+                      ReconnectPolicy::Always,
+                      1,
+                      protocol::current_deadline())
+        };
+        self.leader_notifier.notify();
+        res
     }
 
-    pub fn send(&self, req: &Request) -> Result<Response> {
-        let msg_id = self.alloc_msg_id();
-        let resp = try!(self.core.lock().unwrap().send(msg_id, req));
-        Ok(resp)
+    /// `send_pipelined` sends all the requests on one connection before waiting for
+    /// the responses. It makes only one attempt, and a failed response header is
+    /// returned as is, so callers should retry with `send` if needed.
+    pub fn send_pipelined(&self, label: &str, reqs: &[Request]) -> Result<Vec<Response>> {
+        let _guard = try!(self.acquire_inflight());
+        let res = {
+            let mut core = self.pick_core();
+            let msg_ids: Vec<_> = reqs.iter().map(|_| self.alloc_msg_id()).collect();
+            core.send_pipelined(label, &msg_ids, reqs, protocol::current_deadline())
+        };
+        self.leader_notifier.notify();
+        res
+    }
+
+    fn acquire_inflight(&self) -> Result<Option<InflightGuard>> {
+        match self.inflight {
+            Some(ref limit) => limit.acquire().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Picks an idle core, or the next one in turn if all are busy.
+    fn pick_core(&self) -> MutexGuard<RpcClientCore> {
+        let n = self.cores.len();
+        let start = self.next_core.fetch_add(1, Ordering::SeqCst);
+        for i in 0..n {
+            if let Ok(core) = self.cores[(start + i) % n].try_lock() {
+                return core;
+            }
+        }
+        self.cores[start % n].lock().unwrap()
+    }
+
+    /// Returns the prefix of the log lines of the client, see
+    /// `RpcClientBuilder::log_context`.
+    pub fn log_tag(&self) -> &str {
+        &self.tag
+    }
+
+    // All cores share the same members, so any of them can answer.
+    fn first_core(&self) -> MutexGuard<RpcClientCore> {
+        self.cores[0].lock().unwrap()
+    }
+
+    /// Sets how often the PD members are reloaded when reconnecting.
+    pub fn set_members_refresh_interval(&self, interval: Duration) {
+        for core in self.cores.iter() {
+            core.lock().unwrap().members_refresh_interval = interval;
+        }
+    }
+
+    /// Returns a copy of the last known PD members.
+    pub fn get_members(&self) -> pdpb::GetPDMembersResponse {
+        self.first_core().members.clone()
+    }
+
+    /// Reloads the PD members from any PD node which still responds.
+    pub fn reload_members(&self) -> Result<()> {
+        let members = {
+            let mut core = self.first_core();
+            try!(core.refresh_members());
+            core.members.clone()
+        };
+        for core in self.cores.iter().skip(1) {
+            let mut core = core.lock().unwrap();
+            core.members = members.clone();
+            core.members_refresh_time = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Returns the PD leader of the last known PD members.
+    pub fn get_leader(&self) -> Option<pdpb::PDMember> {
+        let core = self.first_core();
+        if core.members.has_leader() {
+            Some(core.members.get_leader().clone())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the endpoint the client is connected to, or None if there is
+    /// no connection now. With a connection pool, it is the endpoint of the
+    /// first connection which is alive.
+    pub fn current_endpoint(&self) -> Option<String> {
+        for core in self.cores.iter() {
+            let core = core.lock().unwrap();
+            if core.stream.is_some() {
+                return Some(core.endpoint.clone());
+            }
+        }
+        None
+    }
+
+    /// `update_endpoints` replaces the PD endpoints, separated by comma. The new
+    /// endpoints are validated first and must belong to the same cluster, if they
+    /// don't, the client keeps using the old endpoints and connection.
+    pub fn update_endpoints(&self, endpoints: &str) -> Result<()> {
+        let mut eps = vec![];
+        for ep in endpoints.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            eps.push(try!(normalize_endpoint(ep)).to_owned());
+        }
+        let (proxy, timeout) = {
+            let core = self.first_core();
+            (core.proxy.clone(), core.request_timeout)
+        };
+        let (cluster_id, members, latencies) =
+            try!(RpcClient::validate_endpoints_via(&eps, proxy, timeout, self.validate_strategy));
+        if cluster_id != self.cluster_id {
+            return Err(Error::ClusterMismatch(self.cluster_id, cluster_id));
+        }
+        self.first_core().reconnect.lock().unwrap().latencies.extend(latencies);
+
+        info!("{} PD endpoints are updated from {:?} to {:?}",
+              self.tag,
+              self.first_core().endpoints,
+              eps);
+        for core in self.cores.iter() {
+            let mut core = core.lock().unwrap();
+            core.endpoints = eps.clone();
+            core.members = members.clone();
+            core.members_refresh_time = Instant::now();
+            // Reconnect with the new endpoints on the next request.
+            core.stream = None;
+        }
+        Ok(())
+    }
+
+    /// `close` closes the connection to PD. A pending request stops retrying soon,
+    /// and all requests after `close` return an error.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // A busy core is left to drop its connection once it sees the flag, so
+        // closing never waits for a pending request.
+        for core in self.cores.iter() {
+            if let Ok(mut core) = core.try_lock() {
+                core.stream = None;
+            }
+        }
+    }
+
+    /// `health_check` asks PD for its members without changing anything, it returns
+    /// `Ok` if PD answers. It reconnects like other requests, so a successful check
+    /// also replaces a broken connection.
+    pub fn health_check(&self) -> Result<()> {
+        let mut req = protocol::new_request(self.cluster_id, pdpb::CommandType::GetPDMembers);
+        req.set_get_pd_members(pdpb::GetPDMembersRequest::new());
+        try!(self.send("health_check", &req));
+        Ok(())
+    }
+
+    /// Evicts the region from the region cache, callers should call it once they
+    /// find the cached region is stale.
+    pub fn invalidate_region(&self, region_id: u64) {
+        if let Some(ref cache) = self.region_cache {
+            cache.lock().unwrap().invalidate(region_id);
+        }
+    }
+
+    pub fn cached_region(&self, key: &[u8]) -> Option<metapb::Region> {
+        self.region_cache.as_ref().and_then(|cache| cache.lock().unwrap().get_region(key))
+    }
+
+    pub fn cached_region_by_id(&self, region_id: u64) -> Option<metapb::Region> {
+        self.region_cache
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().get_region_by_id(region_id))
+    }
+
+    pub fn cache_region(&self, region: &metapb::Region) {
+        if let Some(ref cache) = self.region_cache {
+            cache.lock().unwrap().insert(region.clone());
+        }
+    }
+
+    /// `cluster_config_cached` is like `get_cluster_config`, but the config is kept
+    /// for the TTL set by `RpcClientBuilder::cluster_config_ttl` and fetched again only
+    /// after it expires, so it suits callers which check the config frequently.
+    pub fn cluster_config_cached(&self) -> Result<metapb::Cluster> {
+        let version = {
+            let cache = self.cluster_config.lock().unwrap();
+            if let Some((ref config, fetch_time)) = cache.config {
+                if fetch_time.elapsed() < self.cluster_config_ttl {
+                    return Ok(config.clone());
+                }
+            }
+            cache.version
+        };
+        // Fetch without the lock, so the other callers don't wait for the retries
+        // while PD is down.
+        let config = try!(self.get_cluster_config());
+        let mut cache = self.cluster_config.lock().unwrap();
+        if cache.version == version {
+            cache.config = Some((config.clone(), Instant::now()));
+        }
+        Ok(config)
+    }
+
+    pub fn invalidate_cluster_config(&self) {
+        let mut cache = self.cluster_config.lock().unwrap();
+        cache.config = None;
+        cache.version += 1;
     }
 
     fn alloc_msg_id(&self) -> u64 {
@@ -200,58 +2027,325 @@ impl RpcClient {
     }
 
     /// `validate_endpoints` validates pd members, make sure they are in the same cluster.
-    /// It returns a cluster ID.
-    /// Notice that it ignores failed pd nodes.
-    /// Export for tests.
-    pub fn validate_endpoints(endpoints: &[String]) -> Result<u64> {
+    /// It returns a cluster ID and the members reported by the first responding node
+    /// in the given order. Notice that it ignores failed pd nodes, and all nodes are
+    /// probed concurrently. Export for tests.
+    pub fn validate_endpoints(endpoints: &[String])
+                              -> Result<(u64, pdpb::GetPDMembersResponse)> {
+        RpcClient::validate_endpoints_via(endpoints,
+                                          None,
+                                          Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+                                          EndpointStrategy::InOrder)
+            .map(|(id, members, _)| (id, members))
+    }
+
+    fn validate_endpoints_via(endpoints: &[String],
+                              proxy: Option<String>,
+                              timeout: Duration,
+                              strategy: EndpointStrategy)
+                              -> Result<(u64,
+                                         pdpb::GetPDMembersResponse,
+                                         HashMap<String, Duration>)> {
         if endpoints.is_empty() {
             return Err(box_err!("empty PD endpoints"));
         }
 
         let len = endpoints.len();
         let mut endpoints_set = HashSet::with_capacity(len);
-
-        let mut cluster_id = None;
         for ep in endpoints {
+            let ep = try!(normalize_endpoint(ep));
             if !endpoints_set.insert(ep) {
                 return Err(box_err!("a duplicate PD url {}", ep));
             }
+        }
 
-            let mut stream = match rpc_connect(ep.as_str()) {
-                Ok(stream) => stream,
-                // Ignore failed pd node.
-                Err(_) => continue,
-            };
+        let mut pending = Vec::with_capacity(len);
+        for (i, ep) in endpoints.iter().enumerate() {
+            pending.push((i, try!(normalize_endpoint(ep)).to_owned()));
+        }
+        // Probe the nodes at the same time, so dead or slow nodes don't add up, by
+        // a few threads which take the nodes one by one. A probe has no more time
+        // than what is left until the deadline, so all threads end soon after it.
+        let deadline = Instant::now() + timeout;
+        let pending = Arc::new(Mutex::new(pending));
+        let (tx, rx) = mpsc::channel();
+        let mut handles = vec![];
+        for _ in 0..cmp::min(len, MAX_PROBE_THREADS) {
+            let (pending, tx, proxy) = (pending.clone(), tx.clone(), proxy.clone());
+            let res = thread::Builder::new()
+                .name(thd_name!("pd-probe"))
+                .spawn(move || {
+                    let proxy = proxy.as_ref().map(|p| p.as_str());
+                    loop {
+                        let (i, ep) = match pending.lock().unwrap().pop() {
+                            Some(node) => node,
+                            None => return,
+                        };
+                        let start = Instant::now();
+                        let res = if start < deadline {
+                            get_members(&ep, proxy, deadline - start)
+                        } else {
+                            Err(box_err!("no time is left to probe it"))
+                        };
+                        let _ = tx.send((i, res.map(|res| (res, start.elapsed()))));
+                    }
+                });
+            match res {
+                Ok(handle) => handles.push(handle),
+                // The started threads probe all nodes anyway.
+                Err(ref e) if !handles.is_empty() => warn!("failed to start a PD probe: {:?}", e),
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+        drop(tx);
+        let mut results: Vec<_> = (0..len).map(|_| None).collect();
+        for (i, res) in rx {
+            results[i] = Some(res);
+        }
+        for handle in handles {
+            let _ = handle.join();
+        }
 
-            let mut req = protocol::new_request(VALIDATE_CLUSTER_ID,
-                                                pdpb::CommandType::GetPDMembers);
-            req.set_get_pd_members(pdpb::GetPDMembersRequest::new());
-            let (mid, resp) = match send_msg(&mut stream, VALIDATE_MSG_ID, &req) {
-                Ok((mid, resp)) => (mid, resp),
+        let mut order: Vec<usize> = (0..len).collect();
+        order_by_strategy(&mut order,
+                          strategy,
+                          |&i| match results[i] {
+                              Some(Ok((_, latency))) => Some(latency),
+                              _ => None,
+                          },
+                          &mut rand::thread_rng());
+
+        let mut cluster_id = None;
+        let mut members = None;
+        let mut latencies = HashMap::new();
+        // The reason of every skipped node, returned if no node is usable.
+        let mut failures = vec![];
+        for i in order {
+            let ep = &endpoints[i];
+            let ((cid, resp), latency) = match results[i].take() {
+                Some(Ok(res)) => res,
                 // Ignore failed pd node.
-                Err(_) => continue,
+                Some(Err(e)) => {
+                    warn!("failed to get members from PD {}: {:?}, skip it", ep, e);
+                    failures.push(format!("{}: {}", ep, e));
+                    continue;
+                }
+                None => {
+                    failures.push(format!("{}: no response", ep));
+                    continue;
+                }
             };
-
-            if mid != VALIDATE_MSG_ID {
-                return Err(box_err!("PD response msg_id mismatch, want {}, got {}",
-                                    VALIDATE_MSG_ID,
-                                    mid));
+            // A PD which is still starting up has no cluster ID yet.
+            if cid == VALIDATE_CLUSTER_ID {
+                warn!("PD {} returns no cluster id, it may not be ready, skip it", ep);
+                failures.push(format!("{}: no cluster id", ep));
+                continue;
             }
 
             // Check cluster ID.
-            let cid = resp.get_header().get_cluster_id();
             if let Some(sample) = cluster_id {
                 if sample != cid {
-                    return Err(box_err!("PD response cluster_id mismatch, want {}, got {}",
-                                        sample,
-                                        cid));
+                    return Err(Error::ClusterMismatch(sample, cid));
                 }
             } else {
                 cluster_id = Some(cid);
             }
+            if members.is_none() {
+                members = Some(resp);
+            }
+            latencies.insert(try!(normalize_endpoint(ep)).to_owned(), latency);
             // TODO: check all fields later?
         }
 
-        cluster_id.ok_or(box_err!("PD cluster stop responding"))
+        match (cluster_id, members) {
+            (Some(cluster_id), Some(members)) => Ok((cluster_id, members, latencies)),
+            _ => Err(box_err!("PD cluster stop responding: [{}]", failures.join(", "))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cmp, io};
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use kvproto::pdpb;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use util::codec;
+
+    use super::{Backoff, ConnectFn, EndpointStrategy, ReconnectPolicy, RpcClientCore,
+                connect_addrs, jitter_ms, log_tag, normalize_endpoint, order_by_strategy,
+                parse_endpoint, rpc_connect, send_err_label};
+    use super::super::{Error, protocol};
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(parse_endpoint("127.0.0.1:2379").unwrap(), ("127.0.0.1", 2379));
+        assert_eq!(parse_endpoint("[::1]:2379").unwrap(), ("::1", 2379));
+        assert_eq!(parse_endpoint("[2001:db8::1]:2379").unwrap(),
+                   ("2001:db8::1", 2379));
+        assert_eq!(parse_endpoint("pd0:2379").unwrap(), ("pd0", 2379));
+
+        assert!(parse_endpoint("localhost").is_err());
+        assert!(parse_endpoint("host:notaport").is_err());
+        assert!(parse_endpoint("[::1]").is_err());
+        assert!(parse_endpoint("::1:2379").is_err());
+        assert!(parse_endpoint(":2379").is_err());
+    }
+
+    #[test]
+    fn test_normalize_endpoint() {
+        assert_eq!(normalize_endpoint("pd0:2379").unwrap(), "pd0:2379");
+        assert_eq!(normalize_endpoint("http://pd0:2379").unwrap(), "pd0:2379");
+        assert_eq!(normalize_endpoint("http://[::1]:2379").unwrap(), "[::1]:2379");
+        assert!(normalize_endpoint("https://pd0:2379").is_err());
+        assert!(normalize_endpoint("unix://pd0:2379").is_err());
+    }
+
+    #[test]
+    fn test_connect_addrs() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // Nothing listens on port 1.
+        let dead = "127.0.0.1:1".parse().unwrap();
+        let alive = listener.local_addr().unwrap();
+
+        let timeout = Duration::from_secs(1);
+        let stream = connect_addrs("pd:2379", &[dead, alive], timeout).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), alive);
+
+        let err = format!("{}", connect_addrs("pd:2379", &[dead], timeout).unwrap_err());
+        assert!(err.contains("127.0.0.1:1"), "{}", err);
+        assert!(connect_addrs("pd:2379", &[], timeout).is_err());
+    }
+
+    #[test]
+    fn test_order_by_strategy() {
+        let eps: Vec<String> = (0..10).map(|i| format!("127.0.0.1:{}", 2379 + i)).collect();
+        let mut latencies = HashMap::new();
+        latencies.insert(eps[3].clone(), Duration::from_millis(10));
+        latencies.insert(eps[1].clone(), Duration::from_millis(20));
+        latencies.insert(eps[7].clone(), Duration::from_millis(20));
+        let seed = [1, 2, 3, 4];
+        let order = |strategy| {
+            let mut items = eps.clone();
+            order_by_strategy(&mut items,
+                              strategy,
+                              |ep| latencies.get(ep).cloned(),
+                              &mut XorShiftRng::from_seed(seed));
+            items
+        };
+        let mut shuffled = eps.clone();
+        XorShiftRng::from_seed(seed).shuffle(&mut shuffled);
+        assert!(shuffled != eps);
+
+        assert_eq!(order(EndpointStrategy::InOrder), eps);
+        assert_eq!(order(EndpointStrategy::Shuffle), shuffled);
+
+        // The fastest first, then the ties and the unknown ones in the shuffled order.
+        let ordered = order(EndpointStrategy::LowLatency);
+        assert_eq!(ordered[0], eps[3]);
+        let ties: Vec<_> = shuffled.iter().filter(|ep| **ep == eps[1] || **ep == eps[7]).collect();
+        assert_eq!(ordered[1..3].iter().collect::<Vec<_>>(), ties);
+        let unknown: Vec<_> = shuffled.iter().filter(|ep| !latencies.contains_key(*ep)).collect();
+        assert_eq!(ordered[3..].iter().collect::<Vec<_>>(), unknown);
+    }
+
+    #[test]
+    fn test_log_tag() {
+        assert_eq!(log_tag(1, &[]), "[pd cluster_id=1]");
+        let context = vec![("zone".to_owned(), "east".to_owned()),
+                           ("subsystem".to_owned(), "gc".to_owned())];
+        assert_eq!(log_tag(2, &context), "[pd cluster_id=2 zone=east subsystem=gc]");
+
+        let core = RpcClientCore::new(vec![],
+                                      3,
+                                      pdpb::GetPDMembersResponse::new(),
+                                      Duration::from_millis(100),
+                                      1);
+        assert_eq!(core.tag, "[pd cluster_id=3]");
+    }
+
+    #[test]
+    fn test_max_retry_count() {
+        // Nothing listens on port 1, so every attempt fails to connect.
+        let endpoints = vec!["127.0.0.1:1".to_owned()];
+        let mut core = RpcClientCore::new(endpoints,
+                                          0,
+                                          pdpb::GetPDMembersResponse::new(),
+                                          Duration::from_millis(100),
+                                          2);
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connects2 = connects.clone();
+        core.connect = ConnectFn(Arc::new(move |ep: &str, timeout| {
+            connects2.fetch_add(1, Ordering::SeqCst);
+            rpc_connect(ep, timeout)
+        }));
+
+        let req = protocol::new_request(0, pdpb::CommandType::AllocId);
+        let err = core.send("alloc_id", 1, &req, ReconnectPolicy::Always, 2, None).unwrap_err();
+        assert!(format!("{:?}", err).contains("after 2 retries"), "{:?}", err);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(100, 1000);
+        for &(min_delay, max_delay) in &[(50, 150), (100, 300), (200, 600), (400, 1000),
+                                         (500, 1000), (500, 1000)] {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(min_delay));
+            assert!(delay <= Duration::from_millis(max_delay));
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_send_last_error() {
+        let endpoints = vec!["127.0.0.1:1".to_owned()];
+        let mut core = RpcClientCore::new(endpoints,
+                                          0,
+                                          pdpb::GetPDMembersResponse::new(),
+                                          Duration::from_millis(100),
+                                          1);
+
+        let req = protocol::new_request(0, pdpb::CommandType::AllocId);
+        let err = core.send("alloc_id", 1, &req, ReconnectPolicy::Always, 1, None).unwrap_err();
+        let err = format!("{}", err);
+        assert!(err.contains("after 1 retries"), "{}", err);
+        assert!(err.contains("failed to connect to"), "{}", err);
+    }
+
+    #[test]
+    fn test_send_err_label() {
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "timeout");
+        assert_eq!(send_err_label(&Error::Io(timeout)), "timeout");
+        let would_block = io::Error::new(io::ErrorKind::WouldBlock, "would block");
+        let e = Error::Codec(codec::Error::Io(would_block));
+        assert_eq!(send_err_label(&e), "timeout");
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert_eq!(send_err_label(&Error::Io(reset)), "rpc_error");
+        assert_eq!(send_err_label(&box_err!("other")), "rpc_error");
+    }
+
+    #[test]
+    fn test_jitter() {
+        let (mut min, mut max) = (Duration::from_secs(10), Duration::from_secs(0));
+        for _ in 0..1000 {
+            let d = jitter_ms(1000);
+            assert!(d >= Duration::from_millis(500) && d <= Duration::from_millis(1500));
+            min = cmp::min(min, d);
+            max = cmp::max(max, d);
+        }
+        // The delays really spread out.
+        assert!(max - min > Duration::from_millis(500));
+        assert_eq!(jitter_ms(0), Duration::from_millis(0));
     }
 }
@@ -0,0 +1,82 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use kvproto::metapb;
+use kvproto::eraftpb::ConfChangeType;
+use kvproto::pdpb::RegionHeartbeatResponse;
+
+/// `HeartbeatDirective` is the scheduling action PD asks the region leader to take
+/// in a region heartbeat response. Splitting is not one of them, the leader asks
+/// PD for a split with `ask_split` instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeartbeatDirective {
+    Noop,
+    ChangePeer(ConfChangeType, metapb::Peer),
+    TransferLeader(metapb::Peer),
+}
+
+impl HeartbeatDirective {
+    /// Classifies the response, a change peer wins if PD sets more than one action.
+    pub fn from_resp(resp: &RegionHeartbeatResponse) -> HeartbeatDirective {
+        if resp.has_change_peer() {
+            let change_peer = resp.get_change_peer();
+            HeartbeatDirective::ChangePeer(change_peer.get_change_type(),
+                                           change_peer.get_peer().clone())
+        } else if resp.has_transfer_leader() {
+            HeartbeatDirective::TransferLeader(resp.get_transfer_leader().get_peer().clone())
+        } else {
+            HeartbeatDirective::Noop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::metapb;
+    use kvproto::eraftpb::ConfChangeType;
+    use kvproto::pdpb::RegionHeartbeatResponse;
+
+    use super::HeartbeatDirective;
+
+    fn new_peer(store_id: u64, peer_id: u64) -> metapb::Peer {
+        let mut peer = metapb::Peer::new();
+        peer.set_store_id(store_id);
+        peer.set_id(peer_id);
+        peer
+    }
+
+    #[test]
+    fn test_heartbeat_directive() {
+        let mut resp = RegionHeartbeatResponse::new();
+        assert_eq!(HeartbeatDirective::from_resp(&resp), HeartbeatDirective::Noop);
+
+        resp.mut_transfer_leader().set_peer(new_peer(2, 2));
+        assert_eq!(HeartbeatDirective::from_resp(&resp),
+                   HeartbeatDirective::TransferLeader(new_peer(2, 2)));
+
+        let mut resp = RegionHeartbeatResponse::new();
+        resp.mut_change_peer().set_change_type(ConfChangeType::AddNode);
+        resp.mut_change_peer().set_peer(new_peer(3, 3));
+        assert_eq!(HeartbeatDirective::from_resp(&resp),
+                   HeartbeatDirective::ChangePeer(ConfChangeType::AddNode, new_peer(3, 3)));
+
+        resp.mut_change_peer().set_change_type(ConfChangeType::RemoveNode);
+        assert_eq!(HeartbeatDirective::from_resp(&resp),
+                   HeartbeatDirective::ChangePeer(ConfChangeType::RemoveNode, new_peer(3, 3)));
+
+        // Change peer goes first.
+        resp.mut_transfer_leader().set_peer(new_peer(2, 2));
+        assert_eq!(HeartbeatDirective::from_resp(&resp),
+                   HeartbeatDirective::ChangePeer(ConfChangeType::RemoveNode, new_peer(3, 3)));
+    }
+}
@@ -15,6 +15,10 @@ use std::error;
 use std::boxed::Box;
 use std::result;
 
+use kvproto::metapb;
+
+use util::escape;
+
 quick_error!{
     #[derive(Debug)]
     pub enum Error {
@@ -36,6 +40,38 @@ quick_error!{
             description("cluster not bootstrap error")
             display("cluster {} is not bootstrapped", cluster_id)
         }
+        ClusterMismatch(want: u64, got: u64) {
+            description("cluster id mismatch")
+            display("cluster id mismatch, want {}, got {}", want, got)
+        }
+        ClusterIdChanged(want: u64, got: u64) {
+            description("cluster id changed")
+            display("cluster id changed from {} to {}, PD may be bootstrapped again", want, got)
+        }
+        RegionNotFound(key: Vec<u8>) {
+            description("region is not found")
+            display("region is not found for key {}", escape(key))
+        }
+        StaleEpoch(region_id: u64, got: metapb::RegionEpoch, want: metapb::RegionEpoch) {
+            description("region epoch is stale")
+            display("region {} epoch {:?} is older than {:?}", region_id, got, want)
+        }
+        SinglePdUnavailable(endpoint: String) {
+            description("the only PD is unavailable")
+            display("the only PD {} is unavailable", endpoint)
+        }
+        TooManyRequests(limit: usize) {
+            description("too many PD requests in flight")
+            display("more than {} PD requests are in flight", limit)
+        }
+        Cancelled {
+            description("request cancelled")
+            display("request is cancelled")
+        }
+        Timeout(msg: String) {
+            description("request timeout")
+            display("request timeout: {}", msg)
+        }
         Other(err: Box<error::Error + Sync + Send>) {
             from()
             cause(err.as_ref())
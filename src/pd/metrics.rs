@@ -11,13 +11,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use prometheus::{Histogram, exponential_buckets};
+use prometheus::{Counter, CounterVec, Histogram, HistogramVec, exponential_buckets};
 
 lazy_static! {
-    pub static ref PD_SEND_MSG_HISTOGRAM: Histogram =
-        register_histogram!(
+    pub static ref PD_SEND_MSG_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!(
             "tikv_pd_msg_send_duration_seconds",
             "Bucketed histogram of PD message send duration",
+             &["type"],
              exponential_buckets(0.0005, 10.0, 7).unwrap()
         ).unwrap();
+
+    pub static ref PD_CONNECT_HISTOGRAM: Histogram =
+        register_histogram!(
+            "tikv_pd_connect_duration_seconds",
+            "Bucketed histogram of PD connection establishment duration",
+             exponential_buckets(0.0005, 10.0, 7).unwrap()
+        ).unwrap();
+
+    pub static ref PD_RETRY_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_pd_request_retry_total",
+            "Total number of PD request retries",
+            &["type"]
+        ).unwrap();
+
+    pub static ref PD_RECONNECT_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_pd_reconnect_total",
+            "Total number of PD reconnect attempts",
+            &["type"]
+        ).unwrap();
+
+    pub static ref PD_LEADER_CHANGE_COUNTER: Counter =
+        register_counter!(
+            "tikv_pd_leader_change_total",
+            "Total number of PD client switching to another PD node"
+        ).unwrap();
+
+    pub static ref PD_REQUEST_FAILURE_COUNTER_VEC: CounterVec =
+        register_counter_vec!(
+            "tikv_pd_request_failure_total",
+            "Total number of failed PD requests",
+            &["type"]
+        ).unwrap();
 }
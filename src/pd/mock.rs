@@ -0,0 +1,352 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kvproto::{metapb, pdpb};
+
+use util::{HandyRwLock, HashMap};
+use raftstore::store::util::is_epoch_stale;
+use super::{Error, PdClient, Result};
+
+pub type ErrorFn = Box<Fn() -> Error + Send + Sync>;
+
+#[derive(Default)]
+struct Cluster {
+    meta: metapb::Cluster,
+    bootstrapped: bool,
+    stores: HashMap<u64, metapb::Store>,
+    regions: HashMap<u64, metapb::Region>,
+    store_stats: HashMap<u64, pdpb::StoreStats>,
+    errors: HashMap<&'static str, ErrorFn>,
+}
+
+impl Cluster {
+    fn get_region(&self, key: &[u8]) -> Option<&metapb::Region> {
+        self.regions.values().find(|r| {
+            key >= r.get_start_key() && (r.get_end_key().is_empty() || key < r.get_end_key())
+        })
+    }
+
+    fn put_region(&mut self, region: metapb::Region) {
+        self.regions.insert(region.get_id(), region);
+    }
+}
+
+/// `MockPdClient` is an in-memory `PdClient` for tests. It keeps stores and
+/// regions in maps, allocates ids and timestamps monotonically, and lets tests
+/// inject an error for any method by its name.
+pub struct MockPdClient {
+    cluster_id: u64,
+    base_id: AtomicUsize,
+    tso: AtomicUsize,
+    cluster: RwLock<Cluster>,
+}
+
+impl MockPdClient {
+    pub fn new(cluster_id: u64) -> MockPdClient {
+        let mut cluster = Cluster::default();
+        cluster.meta.set_id(cluster_id);
+        cluster.meta.set_max_peer_count(3);
+
+        MockPdClient {
+            cluster_id: cluster_id,
+            base_id: AtomicUsize::new(1000),
+            tso: AtomicUsize::new(1),
+            cluster: RwLock::new(cluster),
+        }
+    }
+
+    /// Adds or replaces a store, the cluster is treated as bootstrapped afterwards.
+    pub fn add_store(&self, store: metapb::Store) {
+        let mut cluster = self.cluster.wl();
+        cluster.bootstrapped = true;
+        cluster.stores.insert(store.get_id(), store);
+    }
+
+    /// Adds or replaces a region, the cluster is treated as bootstrapped afterwards.
+    pub fn add_region(&self, region: metapb::Region) {
+        let mut cluster = self.cluster.wl();
+        cluster.bootstrapped = true;
+        cluster.put_region(region);
+    }
+
+    pub fn get_store_stats(&self, store_id: u64) -> Option<pdpb::StoreStats> {
+        self.cluster.rl().store_stats.get(&store_id).cloned()
+    }
+
+    /// Makes the method named `method` fail with the error returned by `f`
+    /// until `clear_error` is called.
+    pub fn set_error<F>(&self, method: &'static str, f: F)
+        where F: Fn() -> Error + Send + Sync + 'static
+    {
+        self.cluster.wl().errors.insert(method, box f);
+    }
+
+    pub fn clear_error(&self, method: &'static str) {
+        self.cluster.wl().errors.remove(method);
+    }
+
+    fn check_error(&self, method: &'static str) -> Result<()> {
+        match self.cluster.rl().errors.get(method) {
+            Some(f) => Err(f()),
+            None => Ok(()),
+        }
+    }
+
+    fn check_bootstrap(&self) -> Result<()> {
+        if !self.cluster.rl().bootstrapped {
+            return Err(Error::ClusterNotBootstrapped(self.cluster_id));
+        }
+        Ok(())
+    }
+}
+
+impl PdClient for MockPdClient {
+    fn get_cluster_id(&self) -> Result<u64> {
+        try!(self.check_error("get_cluster_id"));
+        Ok(self.cluster_id)
+    }
+
+    fn bootstrap_cluster(&self, store: metapb::Store, region: metapb::Region) -> Result<()> {
+        try!(self.check_error("bootstrap_cluster"));
+        // Check and bootstrap under one lock, so only one of concurrent calls wins.
+        let mut cluster = self.cluster.wl();
+        if cluster.bootstrapped {
+            return Err(Error::ClusterBootstrapped(self.cluster_id));
+        }
+        cluster.bootstrapped = true;
+        cluster.stores.insert(store.get_id(), store);
+        cluster.put_region(region);
+        Ok(())
+    }
+
+    fn is_cluster_bootstrapped(&self) -> Result<bool> {
+        try!(self.check_error("is_cluster_bootstrapped"));
+        Ok(self.cluster.rl().bootstrapped)
+    }
+
+    fn alloc_id(&self) -> Result<u64> {
+        try!(self.check_error("alloc_id"));
+        Ok(self.base_id.fetch_add(1, Ordering::SeqCst) as u64)
+    }
+
+    fn get_tso(&self) -> Result<u64> {
+        try!(self.check_error("get_tso"));
+        Ok(self.tso.fetch_add(1, Ordering::SeqCst) as u64)
+    }
+
+    fn put_store(&self, store: metapb::Store) -> Result<()> {
+        try!(self.check_error("put_store"));
+        try!(self.check_bootstrap());
+        self.add_store(store);
+        Ok(())
+    }
+
+    fn get_store(&self, store_id: u64) -> Result<metapb::Store> {
+        try!(self.check_error("get_store"));
+        try!(self.check_bootstrap());
+        match self.cluster.rl().stores.get(&store_id) {
+            Some(store) => Ok(store.clone()),
+            None => Err(box_err!("store {} not found", store_id)),
+        }
+    }
+
+    fn get_cluster_config(&self) -> Result<metapb::Cluster> {
+        try!(self.check_error("get_cluster_config"));
+        try!(self.check_bootstrap());
+        Ok(self.cluster.rl().meta.clone())
+    }
+
+    fn put_cluster_config(&self, config: metapb::Cluster) -> Result<()> {
+        try!(self.check_error("put_cluster_config"));
+        try!(self.check_bootstrap());
+        if config.get_id() != self.cluster_id {
+            return Err(box_err!("invalid cluster id {}", config.get_id()));
+        }
+        self.cluster.wl().meta = config;
+        Ok(())
+    }
+
+    fn get_region(&self, key: &[u8]) -> Result<metapb::Region> {
+        try!(self.check_error("get_region"));
+        try!(self.check_bootstrap());
+        match self.cluster.rl().get_region(key) {
+            Some(region) => Ok(region.clone()),
+            None => Err(Error::RegionNotFound(key.to_vec())),
+        }
+    }
+
+    fn get_region_by_id(&self, region_id: u64) -> Result<Option<metapb::Region>> {
+        try!(self.check_error("get_region_by_id"));
+        try!(self.check_bootstrap());
+        Ok(self.cluster.rl().regions.get(&region_id).cloned())
+    }
+
+    fn region_heartbeat(&self,
+                        region: metapb::Region,
+                        _: metapb::Peer,
+                        _: Vec<pdpb::PeerStats>,
+                        _: Vec<metapb::Peer>)
+                        -> Result<pdpb::RegionHeartbeatResponse> {
+        try!(self.check_error("region_heartbeat"));
+        try!(self.check_bootstrap());
+        let mut cluster = self.cluster.wl();
+        let want = match cluster.regions.get(&region.get_id()) {
+            Some(cur) if is_epoch_stale(region.get_region_epoch(), cur.get_region_epoch()) => {
+                Some(cur.get_region_epoch().clone())
+            }
+            _ => None,
+        };
+        if let Some(want) = want {
+            return Err(Error::StaleEpoch(region.get_id(),
+                                         region.get_region_epoch().clone(),
+                                         want));
+        }
+        cluster.put_region(region);
+        Ok(pdpb::RegionHeartbeatResponse::new())
+    }
+
+    fn ask_split(&self, region: metapb::Region) -> Result<pdpb::AskSplitResponse> {
+        try!(self.check_error("ask_split"));
+        try!(self.check_bootstrap());
+        let mut resp = pdpb::AskSplitResponse::new();
+        resp.set_new_region_id(try!(self.alloc_id()));
+        let mut peer_ids = vec![];
+        for _ in region.get_peers() {
+            peer_ids.push(try!(self.alloc_id()));
+        }
+        resp.set_new_peer_ids(peer_ids);
+        Ok(resp)
+    }
+
+    fn store_heartbeat(&self, stats: pdpb::StoreStats) -> Result<pdpb::StoreHeartbeatResponse> {
+        try!(self.check_error("store_heartbeat"));
+        try!(self.check_bootstrap());
+        self.cluster.wl().store_stats.insert(stats.get_store_id(), stats);
+        Ok(pdpb::StoreHeartbeatResponse::new())
+    }
+
+    fn report_split(&self, left: metapb::Region, right: metapb::Region) -> Result<()> {
+        try!(self.check_error("report_split"));
+        try!(self.check_bootstrap());
+        let mut cluster = self.cluster.wl();
+        cluster.put_region(left);
+        cluster.put_region(right);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::metapb;
+
+    use pd::{Error, PdClient, StoreStatsBuilder};
+    use super::MockPdClient;
+
+    fn new_region(id: u64, start_key: &[u8], end_key: &[u8]) -> metapb::Region {
+        let mut region = metapb::Region::new();
+        region.set_id(id);
+        region.set_start_key(start_key.to_vec());
+        region.set_end_key(end_key.to_vec());
+        region
+    }
+
+    #[test]
+    fn test_mock_pd_client() {
+        let client = MockPdClient::new(1);
+        assert!(!client.is_cluster_bootstrapped().unwrap());
+        assert!(client.get_region(b"a").is_err());
+
+        let mut store = metapb::Store::new();
+        store.set_id(1);
+        client.bootstrap_cluster(store, new_region(2, b"", b"")).unwrap();
+        match client.bootstrap_cluster(metapb::Store::new(), metapb::Region::new()) {
+            Err(Error::ClusterBootstrapped(1)) => {}
+            other => panic!("expect bootstrapped error, got {:?}", other),
+        }
+
+        client.report_split(new_region(2, b"", b"k"), new_region(3, b"k", b"")).unwrap();
+        assert_eq!(client.get_region(b"a").unwrap().get_id(), 2);
+        assert_eq!(client.get_region(b"k").unwrap().get_id(), 3);
+        assert_eq!(client.get_region_by_id(3).unwrap().unwrap().get_start_key(), b"k");
+        assert!(client.get_region_by_id(4).unwrap().is_none());
+
+        let mut region = client.get_region_by_id(3).unwrap().unwrap();
+        region.mut_region_epoch().set_version(2);
+        client.region_heartbeat(region.clone(), metapb::Peer::new(), vec![], vec![]).unwrap();
+        region.mut_region_epoch().set_version(1);
+        match client.region_heartbeat(region, metapb::Peer::new(), vec![], vec![]) {
+            Err(Error::StaleEpoch(3, ref got, ref want)) if got.get_version() == 1 &&
+                                                            want.get_version() == 2 => {}
+            other => panic!("expect stale epoch error, got {:?}", other),
+        }
+
+        let id = client.alloc_id().unwrap();
+        assert!(client.alloc_id().unwrap() > id);
+        let ts = client.get_tso().unwrap();
+        assert!(client.get_tso().unwrap() > ts);
+
+        client.set_error("get_store", || box_err!("injected"));
+        assert!(client.get_store(1).is_err());
+        client.clear_error("get_store");
+        assert_eq!(client.get_store(1).unwrap().get_id(), 1);
+    }
+
+    #[test]
+    fn test_store_heartbeat() {
+        let client = MockPdClient::new(1);
+        let mut store = metapb::Store::new();
+        store.set_id(1);
+        client.bootstrap_cluster(store, new_region(2, b"", b"")).unwrap();
+
+        let stats = StoreStatsBuilder::new(1)
+            .space(100, 40)
+            .region_count(1)
+            .snap_count(0, 1, 0)
+            .build()
+            .unwrap();
+        client.store_heartbeat(stats.clone()).unwrap();
+        assert_eq!(client.get_store_stats(1), Some(stats));
+        assert!(client.get_store_stats(2).is_none());
+    }
+
+    #[test]
+    fn test_get_region_replicas() {
+        let client = MockPdClient::new(1);
+        let states = [metapb::StoreState::Up,
+                      metapb::StoreState::Offline,
+                      metapb::StoreState::Tombstone];
+        let mut region = new_region(1, b"", b"");
+        for (i, state) in states.iter().enumerate() {
+            let mut store = metapb::Store::new();
+            store.set_id(i as u64 + 1);
+            store.set_state(*state);
+            client.add_store(store);
+
+            let mut peer = metapb::Peer::new();
+            peer.set_id(i as u64 + 10);
+            peer.set_store_id(i as u64 + 1);
+            region.mut_peers().push(peer);
+        }
+        client.add_region(region);
+
+        let replicas = client.get_region_replicas(b"a").unwrap();
+        let replicas: Vec<_> = replicas.iter().map(|&(ref p, up)| (p.get_id(), up)).collect();
+        assert_eq!(replicas, vec![(10, true), (11, false), (12, false)]);
+
+        client.set_error("get_store", || box_err!("injected"));
+        assert!(client.get_region_replicas(b"a").is_err());
+    }
+}
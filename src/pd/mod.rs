@@ -11,23 +11,86 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 mod client;
 mod protocol;
 mod metrics;
+mod region_cache;
+mod directive;
+mod stats;
 
 pub mod errors;
+pub mod mock;
 pub use self::errors::{Result, Error};
-pub use self::client::RpcClient;
+pub use self::client::{Connector, EndpointStrategy, OverloadPolicy, ReconnectPolicy, RpcClient,
+                       RpcClientBuilder, rpc_connect};
+pub use self::protocol::{with_cancel, with_deadline, with_trace_id};
+pub use self::directive::HeartbeatDirective;
+pub use self::stats::{StoreStatsBuilder, new_peer_stats};
+pub use self::mock::MockPdClient;
 
 use kvproto::metapb;
 use kvproto::pdpb;
 
+use raftstore::store::util::is_epoch_stale;
+use util::HashMap;
+use self::client::Backoff;
+
 pub type Key = Vec<u8>;
 
 pub const INVALID_ID: u64 = 0;
 
+const WAIT_BOOTSTRAPPED_BACKOFF_BASE_MS: u64 = 100;
+const WAIT_BOOTSTRAPPED_BACKOFF_MAX_MS: u64 = 1000;
+
+const STALE_EPOCH_RETRY_COUNT: usize = 3;
+const STALE_EPOCH_BACKOFF_BASE_MS: u64 = 100;
+const STALE_EPOCH_BACKOFF_MAX_MS: u64 = 500;
+
+// Calls `get` until the region it returns is not older than `min_epoch`, `get` is
+// passed the number of the attempt. Pd learns a new epoch from the heartbeat
+// of the region leader, so it may lag behind the caller for a while.
+fn get_fresh_region<F>(min_epoch: &metapb::RegionEpoch,
+                       mut get: F)
+                       -> Result<Option<metapb::Region>>
+    where F: FnMut(usize) -> Result<Option<metapb::Region>>
+{
+    let mut backoff = Backoff::new(STALE_EPOCH_BACKOFF_BASE_MS, STALE_EPOCH_BACKOFF_MAX_MS);
+    let mut attempt = 0;
+    loop {
+        let region = match try!(get(attempt)) {
+            Some(region) => region,
+            None => return Ok(None),
+        };
+        if !is_epoch_stale(region.get_region_epoch(), min_epoch) {
+            return Ok(Some(region));
+        }
+        if attempt >= STALE_EPOCH_RETRY_COUNT {
+            return Err(Error::StaleEpoch(region.get_id(),
+                                         region.get_region_epoch().clone(),
+                                         min_epoch.clone()));
+        }
+        warn!("pd returns region {} with stale epoch {:?}, want {:?}, retry",
+              region.get_id(),
+              region.get_region_epoch(),
+              min_epoch);
+        attempt += 1;
+        thread::sleep(backoff.next_delay());
+    }
+}
+
+// The lower bits of a timestamp are the logical part, the rest is physical time in ms.
+pub const TSO_PHYSICAL_SHIFT_BITS: u64 = 18;
+
+// Compose the physical and logical parts of a PD timestamp into one u64.
+pub fn compose_ts(physical: i64, logical: i64) -> u64 {
+    ((physical as u64) << TSO_PHYSICAL_SHIFT_BITS) + logical as u64
+}
+
 // Client to communicate with placement driver (pd) for special cluster.
 // Because now one pd only supports one cluster, so it is no need to pass
 // cluster id in trait interface every time, so passing the cluster id when
@@ -53,9 +116,43 @@ pub trait PdClient: Send + Sync {
     // and panic if not bootstrapped.
     fn is_cluster_bootstrapped(&self) -> Result<bool>;
 
+    // Wait until the cluster is bootstrapped by another node, checking it
+    // with backoff. Return Timeout error if it is still not bootstrapped
+    // after `timeout`, errors of the checks are only logged.
+    fn wait_bootstrapped(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(WAIT_BOOTSTRAPPED_BACKOFF_BASE_MS,
+                                       WAIT_BOOTSTRAPPED_BACKOFF_MAX_MS);
+        loop {
+            match self.is_cluster_bootstrapped() {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => warn!("failed to check whether cluster is bootstrapped: {:?}", e),
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(Error::Timeout(format!("cluster is not bootstrapped after {:?}",
+                                                  elapsed)));
+            }
+            thread::sleep(cmp::min(backoff.next_delay(), timeout - elapsed));
+        }
+    }
+
     // Allocate a unique positive id.
     fn alloc_id(&self) -> Result<u64>;
 
+    // Like alloc_id, but make only one attempt and return its error at once,
+    // so the caller is never blocked by retrying when pd is unreachable.
+    // Only RpcClient guarantees it, the default just calls alloc_id, which
+    // suits the clients that never retry.
+    fn try_alloc_id(&self) -> Result<u64> {
+        self.alloc_id()
+    }
+
+    // Get a timestamp from the timestamp oracle in pd, timestamps
+    // are strictly increasing across the whole cluster.
+    fn get_tso(&self) -> Result<u64>;
+
     // When the store starts, or some store information changed, it
     // uses put_store to inform pd.
     fn put_store(&self, store: metapb::Store) -> Result<()>;
@@ -77,13 +174,74 @@ pub trait PdClient: Send + Sync {
     // Get cluster meta information.
     fn get_cluster_config(&self) -> Result<metapb::Cluster>;
 
+    // Update cluster meta information, like max_peer_count.
+    // The cluster ID in config must be the same as the cluster ID of pd.
+    fn put_cluster_config(&self, config: metapb::Cluster) -> Result<()>;
+
     // For route.
-    // Get region which the key belong to.
+    // Get region which the key belong to, RegionNotFound is returned if pd
+    // knows no region containing it.
     fn get_region(&self, key: &[u8]) -> Result<metapb::Region>;
 
-    // Get region by region id.
+    // Get region which the key belong to and its leader peer in one call,
+    // the leader is None if pd doesn't know it.
+    fn get_region_and_leader(&self, key: &[u8]) -> Result<(metapb::Region, Option<metapb::Peer>)> {
+        let region = try!(self.get_region(key));
+        Ok((region, None))
+    }
+
+    // Get the peers of the region which the key belongs to, each one is paired with
+    // whether its store is up. A store is asked for only once even if it holds
+    // several peers.
+    fn get_region_replicas(&self, key: &[u8]) -> Result<Vec<(metapb::Peer, bool)>> {
+        let region = try!(self.get_region(key));
+        let mut store_up = HashMap::default();
+        let mut replicas = Vec::with_capacity(region.get_peers().len());
+        for peer in region.get_peers() {
+            let store_id = peer.get_store_id();
+            if !store_up.contains_key(&store_id) {
+                let store = try!(self.get_store(store_id));
+                store_up.insert(store_id, store.get_state() == metapb::StoreState::Up);
+            }
+            replicas.push((peer.clone(), store_up[&store_id]));
+        }
+        Ok(replicas)
+    }
+
+    // Get region by region id, None is returned if pd doesn't know it.
     fn get_region_by_id(&self, region_id: u64) -> Result<Option<metapb::Region>>;
 
+    // Get regions by ids, the result is in the same order as `ids` and
+    // a region pd doesn't know is None.
+    fn get_regions_by_ids(&self, ids: &[u64]) -> Result<Vec<Option<metapb::Region>>> {
+        let mut regions = Vec::with_capacity(ids.len());
+        for &id in ids {
+            regions.push(try!(self.get_region_by_id(id)));
+        }
+        Ok(regions)
+    }
+
+    // Like get_region, but the epoch of the returned region is not older than
+    // `min_epoch`, which the caller has seen somewhere else. Pd is asked again
+    // for a while if it only knows an older one, then StaleEpoch error is returned.
+    fn get_region_with_epoch(&self,
+                             key: &[u8],
+                             min_epoch: &metapb::RegionEpoch)
+                             -> Result<metapb::Region> {
+        match try!(get_fresh_region(min_epoch, |_| self.get_region(key).map(Some))) {
+            Some(region) => Ok(region),
+            None => Err(Error::RegionNotFound(key.to_vec())),
+        }
+    }
+
+    // Like get_region_by_id, but checks the epoch as get_region_with_epoch does.
+    fn get_region_by_id_with_epoch(&self,
+                                   region_id: u64,
+                                   min_epoch: &metapb::RegionEpoch)
+                                   -> Result<Option<metapb::Region>> {
+        get_fresh_region(min_epoch, |_| self.get_region_by_id(region_id))
+    }
+
     // Leader for a region will use this to heartbeat Pd.
     fn region_heartbeat(&self,
                         region: metapb::Region,
@@ -96,7 +254,7 @@ pub trait PdClient: Send + Sync {
     fn ask_split(&self, region: metapb::Region) -> Result<pdpb::AskSplitResponse>;
 
     // Send store statistics regularly.
-    fn store_heartbeat(&self, stats: pdpb::StoreStats) -> Result<()>;
+    fn store_heartbeat(&self, stats: pdpb::StoreStats) -> Result<pdpb::StoreHeartbeatResponse>;
 
     // Report pd the split region.
     fn report_split(&self, left: metapb::Region, right: metapb::Region) -> Result<()>;
@@ -11,10 +11,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
 use uuid::Uuid;
 use kvproto::{metapb, pdpb};
 use protobuf::RepeatedField;
-use super::{Error, Result, RpcClient};
+use super::{Error, INVALID_ID, Result, RpcClient, compose_ts, get_fresh_region};
+
+// At most so many requests are sent in one pipeline, so a batch doesn't flood PD.
+const MAX_PIPELINED_REQUESTS: usize = 16;
 
 impl super::PdClient for RpcClient {
     fn get_cluster_id(&self) -> Result<u64> {
@@ -25,29 +34,46 @@ impl super::PdClient for RpcClient {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::GetPDMembers);
         req.set_get_pd_members(get_pd_members);
 
-        let mut resp = try!(self.send(&req));
-        try!(check_resp(&resp));
+        let mut resp = try!(self.send("get_cluster_id", &req));
         Ok(resp.take_header().get_cluster_id())
     }
 
     fn bootstrap_cluster(&self, store: metapb::Store, region: metapb::Region) -> Result<()> {
-        let mut bootstrap = pdpb::BootstrapRequest::new();
-        bootstrap.set_store(store);
-        bootstrap.set_region(region);
-
-        let mut req = new_request(self.cluster_id, pdpb::CommandType::Bootstrap);
-        req.set_bootstrap(bootstrap);
-
-        let resp = try!(self.send(&req));
-        check_resp(&resp)
+        let region_id = region.get_id();
+        let store_id = store.get_id();
+        let epoch = region.get_region_epoch().clone();
+        // It costs an extra round trip on every bootstrap, but a bootstrapped cluster
+        // is told apart without building and sending the whole request.
+        let res = if try!(self.is_cluster_bootstrapped()) {
+            Err(Error::ClusterBootstrapped(self.cluster_id))
+        } else {
+            let req = try!(new_bootstrap_request(self.cluster_id, store, region));
+            self.send("bootstrap_cluster", &req).map(|_| ())
+        };
+        match res {
+            // The call or the request may be retried after PD bootstrapped the cluster
+            // with it but the response got lost, then the cluster contains our region,
+            // with the same epoch and peers on our store.
+            Err(Error::ClusterBootstrapped(id)) => {
+                match self.get_region_by_id(region_id) {
+                    Ok(Some(ref r)) if is_bootstrap_region(r, store_id, &epoch) => {
+                        info!("{} cluster {} is bootstrapped by a retried request",
+                              self.log_tag(),
+                              id);
+                        Ok(())
+                    }
+                    _ => Err(Error::ClusterBootstrapped(id)),
+                }
+            }
+            res => res,
+        }
     }
 
     fn is_cluster_bootstrapped(&self) -> Result<bool> {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::IsBootstrapped);
         req.set_is_bootstrapped(pdpb::IsBootstrappedRequest::new());
 
-        let resp = try!(self.send(&req));
-        try!(check_resp(&resp));
+        let resp = try!(self.send("is_cluster_bootstrapped", &req));
         Ok(resp.get_is_bootstrapped().get_bootstrapped())
     }
 
@@ -55,20 +81,37 @@ impl super::PdClient for RpcClient {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::AllocId);
         req.set_alloc_id(pdpb::AllocIdRequest::new());
 
-        let resp = try!(self.send(&req));
-        try!(check_resp(&resp));
+        let resp = try!(self.send("alloc_id", &req));
         Ok(resp.get_alloc_id().get_id())
     }
 
-    fn put_store(&self, store: metapb::Store) -> Result<()> {
-        let mut put_store = pdpb::PutStoreRequest::new();
-        put_store.set_store(store);
+    fn try_alloc_id(&self) -> Result<u64> {
+        let mut req = new_request(self.cluster_id, pdpb::CommandType::AllocId);
+        req.set_alloc_id(pdpb::AllocIdRequest::new());
+
+        let resp = try!(self.send_once("alloc_id", &req));
+        Ok(resp.get_alloc_id().get_id())
+    }
 
-        let mut req = new_request(self.cluster_id, pdpb::CommandType::PutStore);
-        req.set_put_store(put_store);
+    fn get_tso(&self) -> Result<u64> {
+        let mut tso = pdpb::TsoRequest::new();
+        tso.set_number(1);
 
-        let resp = try!(self.send(&req));
-        check_resp(&resp)
+        let mut req = new_request(self.cluster_id, pdpb::CommandType::Tso);
+        req.set_tso(tso);
+
+        let resp = try!(self.send("get_tso", &req));
+        let timestamps = resp.get_tso().get_timestamps();
+        if timestamps.len() != 1 {
+            return Err(box_err!("pd returns {} timestamps, want 1", timestamps.len()));
+        }
+        Ok(compose_ts(timestamps[0].get_physical(), timestamps[0].get_logical()))
+    }
+
+    fn put_store(&self, store: metapb::Store) -> Result<()> {
+        let req = try!(new_put_store_request(self.cluster_id, store));
+        try!(self.send("put_store", &req));
+        Ok(())
     }
 
     fn get_store(&self, store_id: u64) -> Result<metapb::Store> {
@@ -78,8 +121,7 @@ impl super::PdClient for RpcClient {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::GetStore);
         req.set_get_store(get_store);
 
-        let mut resp = try!(self.send(&req));
-        try!(check_resp(&resp));
+        let mut resp = try!(self.send("get_store", &req));
         Ok(resp.take_get_store().take_store())
     }
 
@@ -87,37 +129,117 @@ impl super::PdClient for RpcClient {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::GetClusterConfig);
         req.set_get_cluster_config(pdpb::GetClusterConfigRequest::new());
 
-        let mut resp = try!(self.send(&req));
-        try!(check_resp(&resp));
+        let mut resp = try!(self.send("get_cluster_config", &req));
         Ok(resp.take_get_cluster_config().take_cluster())
     }
 
+    fn put_cluster_config(&self, config: metapb::Cluster) -> Result<()> {
+        let req = try!(new_put_cluster_config_request(self.cluster_id, config));
+        // The request may have changed the config even if it fails.
+        let res = self.send("put_cluster_config", &req);
+        self.invalidate_cluster_config();
+        try!(res);
+        Ok(())
+    }
+
     fn get_region(&self, key: &[u8]) -> Result<metapb::Region> {
+        if let Some(region) = self.cached_region(key) {
+            return Ok(region);
+        }
+
+        self.get_region_and_leader(key).map(|(region, _)| region)
+    }
+
+    fn get_region_and_leader(&self, key: &[u8]) -> Result<(metapb::Region, Option<metapb::Peer>)> {
+        // The leader changes much more often than the region, so it is never cached.
         let mut get_region = pdpb::GetRegionRequest::new();
         get_region.set_region_key(key.to_vec());
 
         let mut req = new_request(self.cluster_id, pdpb::CommandType::GetRegion);
         req.set_get_region(get_region);
 
-        let mut resp = try!(self.send(&req));
-        try!(check_resp(&resp));
-        Ok(resp.take_get_region().take_region())
+        let mut resp = try!(self.send("get_region", &req));
+        let mut get_region = resp.take_get_region();
+        if is_empty_region(get_region.get_region()) {
+            return Err(Error::RegionNotFound(key.to_vec()));
+        }
+        let region = get_region.take_region();
+        self.cache_region(&region);
+        let leader = if get_region.has_leader() {
+            Some(get_region.take_leader())
+        } else {
+            None
+        };
+        Ok((region, leader))
+    }
+
+    fn get_region_with_epoch(&self,
+                             key: &[u8],
+                             min_epoch: &metapb::RegionEpoch)
+                             -> Result<metapb::Region> {
+        // The cached region is older than the caller's, only pd may know a newer one.
+        let res = get_fresh_region(min_epoch, |attempt| {
+            let region = if attempt == 0 {
+                try!(self.get_region(key))
+            } else {
+                try!(self.get_region_and_leader(key)).0
+            };
+            Ok(Some(region))
+        });
+        match try!(res) {
+            Some(region) => Ok(region),
+            None => Err(Error::RegionNotFound(key.to_vec())),
+        }
+    }
+
+    fn get_region_by_id_with_epoch(&self,
+                                   region_id: u64,
+                                   min_epoch: &metapb::RegionEpoch)
+                                   -> Result<Option<metapb::Region>> {
+        get_fresh_region(min_epoch, |attempt| {
+            if attempt > 0 {
+                self.invalidate_region(region_id);
+            }
+            self.get_region_by_id(region_id)
+        })
     }
 
     fn get_region_by_id(&self, region_id: u64) -> Result<Option<metapb::Region>> {
-        let mut get_region_by_id = pdpb::GetRegionByIDRequest::new();
-        get_region_by_id.set_region_id(region_id);
+        if let Some(region) = self.cached_region_by_id(region_id) {
+            return Ok(Some(region));
+        }
 
-        let mut req = new_request(self.cluster_id, pdpb::CommandType::GetRegionByID);
-        req.set_get_region_by_id(get_region_by_id);
+        let req = new_get_region_by_id_request(self.cluster_id, region_id);
+        let resp = try!(self.send("get_region_by_id", &req));
+        Ok(self.take_region_by_id(resp))
+    }
 
-        let mut resp = try!(self.send(&req));
-        try!(check_resp(&resp));
-        if resp.get_get_region_by_id().has_region() {
-            Ok(Some(resp.take_get_region_by_id().take_region()))
-        } else {
-            Ok(None)
+    fn get_regions_by_ids(&self, ids: &[u64]) -> Result<Vec<Option<metapb::Region>>> {
+        let mut regions: Vec<_> = ids.iter().map(|&id| self.cached_region_by_id(id)).collect();
+        let missing: Vec<_> = (0..ids.len()).filter(|&i| regions[i].is_none()).collect();
+        for batch in missing.chunks(MAX_PIPELINED_REQUESTS) {
+            let reqs: Vec<_> = batch.iter()
+                .map(|&i| new_get_region_by_id_request(self.cluster_id, ids[i]))
+                .collect();
+            let resps = match self.send_pipelined("get_region_by_id", &reqs) {
+                Ok(resps) => resps.into_iter().map(Some).collect(),
+                Err(e) => {
+                    warn!("{} failed to pipeline {} get_region_by_id requests: {:?}",
+                          self.log_tag(),
+                          reqs.len(),
+                          e);
+                    vec![None; reqs.len()]
+                }
+            };
+            for ((&i, req), mut resp) in batch.iter().zip(&reqs).zip(resps) {
+                // Send the failed ones again with retrying.
+                if resp.as_ref().map_or(true, |r| is_failed_resp(self.cluster_id, r)) {
+                    resp = Some(try!(self.send("get_region_by_id", req)));
+                }
+                regions[i] = self.take_region_by_id(resp.unwrap());
+            }
         }
+        Ok(regions)
     }
 
     fn region_heartbeat(&self,
@@ -126,6 +248,7 @@ impl super::PdClient for RpcClient {
                         down_peers: Vec<pdpb::PeerStats>,
                         pending_peers: Vec<metapb::Peer>)
                         -> Result<pdpb::RegionHeartbeatResponse> {
+        let region_id = region.get_id();
         let mut heartbeat = pdpb::RegionHeartbeatRequest::new();
         heartbeat.set_region(region);
         heartbeat.set_leader(leader);
@@ -135,9 +258,14 @@ impl super::PdClient for RpcClient {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::RegionHeartbeat);
         req.set_region_heartbeat(heartbeat);
 
-        let mut resp = try!(self.send(&req));
-        try!(check_resp(&resp));
-        Ok(resp.take_region_heartbeat())
+        match self.send("region_heartbeat", &req) {
+            Ok(mut resp) => Ok(resp.take_region_heartbeat()),
+            Err(e) => {
+                // The region may be stale, like an epoch mismatch, don't serve it from cache.
+                self.invalidate_region(region_id);
+                Err(e)
+            }
+        }
     }
 
     fn ask_split(&self, region: metapb::Region) -> Result<pdpb::AskSplitResponse> {
@@ -147,23 +275,25 @@ impl super::PdClient for RpcClient {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::AskSplit);
         req.set_ask_split(ask_split);
 
-        let mut resp = try!(self.send(&req));
-        try!(check_resp(&resp));
+        let mut resp = try!(self.send("ask_split", &req));
         Ok(resp.take_ask_split())
     }
 
-    fn store_heartbeat(&self, stats: pdpb::StoreStats) -> Result<()> {
+    fn store_heartbeat(&self, stats: pdpb::StoreStats) -> Result<pdpb::StoreHeartbeatResponse> {
         let mut heartbeat = pdpb::StoreHeartbeatRequest::new();
         heartbeat.set_stats(stats);
 
         let mut req = new_request(self.cluster_id, pdpb::CommandType::StoreHeartbeat);
         req.set_store_heartbeat(heartbeat);
 
-        let resp = try!(self.send(&req));
-        check_resp(&resp)
+        let mut resp = try!(self.send("store_heartbeat", &req));
+        Ok(resp.take_store_heartbeat())
     }
 
     fn report_split(&self, left: metapb::Region, right: metapb::Region) -> Result<()> {
+        self.invalidate_region(left.get_id());
+        self.invalidate_region(right.get_id());
+
         let mut report_split = pdpb::ReportSplitRequest::new();
         report_split.set_left(left);
         report_split.set_right(right);
@@ -171,26 +301,246 @@ impl super::PdClient for RpcClient {
         let mut req = new_request(self.cluster_id, pdpb::CommandType::ReportSplit);
         req.set_report_split(report_split);
 
-        let resp = try!(self.send(&req));
-        check_resp(&resp)
+        try!(self.send("report_split", &req));
+        Ok(())
+    }
+}
+
+impl RpcClient {
+    fn take_region_by_id(&self, mut resp: pdpb::Response) -> Option<metapb::Region> {
+        if is_empty_region(resp.get_get_region_by_id().get_region()) {
+            return None;
+        }
+        let region = resp.take_get_region_by_id().take_region();
+        self.cache_region(&region);
+        Some(region)
+    }
+}
+
+// PD may answer an empty region instead of none when it finds no region, both
+// mean the region is not found. The getter returns an empty region if none is set.
+fn is_empty_region(region: &metapb::Region) -> bool {
+    region.get_id() == INVALID_ID
+}
+
+fn new_get_region_by_id_request(cluster_id: u64, region_id: u64) -> pdpb::Request {
+    let mut get_region_by_id = pdpb::GetRegionByIDRequest::new();
+    get_region_by_id.set_region_id(region_id);
+
+    let mut req = new_request(cluster_id, pdpb::CommandType::GetRegionByID);
+    req.set_get_region_by_id(get_region_by_id);
+    req
+}
+
+// Returns true if `region` is the first region of a cluster bootstrapped on the store.
+fn is_bootstrap_region(region: &metapb::Region,
+                       store_id: u64,
+                       epoch: &metapb::RegionEpoch)
+                       -> bool {
+    region.get_region_epoch() == epoch && !region.get_peers().is_empty() &&
+    region.get_peers().iter().all(|p| p.get_store_id() == store_id)
+}
+
+// Returns true if the response header has an error, or comes from another cluster.
+fn is_failed_resp(cluster_id: u64, resp: &pdpb::Response) -> bool {
+    if !resp.has_header() {
+        return true;
+    }
+    let header = resp.get_header();
+    header.get_cluster_id() != cluster_id || header.has_error()
+}
+
+/// The dry-run methods build and check the request of a mutating call like the call
+/// itself does, but return the request instead of sending it, so PD is left untouched.
+impl RpcClient {
+    pub fn dry_run_bootstrap_cluster(&self,
+                                     store: metapb::Store,
+                                     region: metapb::Region)
+                                     -> Result<pdpb::Request> {
+        // Whether the cluster is bootstrapped already can only be known by asking PD.
+        new_bootstrap_request(self.cluster_id, store, region)
+    }
+
+    pub fn dry_run_put_store(&self, store: metapb::Store) -> Result<pdpb::Request> {
+        new_put_store_request(self.cluster_id, store)
+    }
+
+    pub fn dry_run_put_cluster_config(&self, config: metapb::Cluster) -> Result<pdpb::Request> {
+        new_put_cluster_config_request(self.cluster_id, config)
+    }
+}
+
+// PD rejects a store or region without id, so check it before sending.
+fn check_ids(store: &metapb::Store, region: Option<&metapb::Region>) -> Result<()> {
+    if store.get_id() == INVALID_ID {
+        return Err(box_err!("invalid store id {} in {:?}", INVALID_ID, store));
     }
+    if let Some(region) = region {
+        if region.get_id() == INVALID_ID {
+            return Err(box_err!("invalid region id {} in {:?}", INVALID_ID, region));
+        }
+    }
+    Ok(())
+}
+
+fn new_bootstrap_request(cluster_id: u64,
+                         store: metapb::Store,
+                         region: metapb::Region)
+                         -> Result<pdpb::Request> {
+    try!(check_ids(&store, Some(&region)));
+
+    let mut bootstrap = pdpb::BootstrapRequest::new();
+    bootstrap.set_store(store);
+    bootstrap.set_region(region);
+
+    let mut req = new_request(cluster_id, pdpb::CommandType::Bootstrap);
+    req.set_bootstrap(bootstrap);
+    Ok(req)
+}
+
+fn new_put_store_request(cluster_id: u64, store: metapb::Store) -> Result<pdpb::Request> {
+    try!(check_ids(&store, None));
+
+    let mut put_store = pdpb::PutStoreRequest::new();
+    put_store.set_store(store);
+
+    let mut req = new_request(cluster_id, pdpb::CommandType::PutStore);
+    req.set_put_store(put_store);
+    Ok(req)
+}
+
+fn new_put_cluster_config_request(cluster_id: u64,
+                                  config: metapb::Cluster)
+                                  -> Result<pdpb::Request> {
+    if config.get_id() != cluster_id {
+        return Err(box_err!("cluster id {} in config mismatches pd cluster id {}",
+                            config.get_id(),
+                            cluster_id));
+    }
+
+    let mut put_cluster_config = pdpb::PutClusterConfigRequest::new();
+    put_cluster_config.set_cluster(config);
+
+    let mut req = new_request(cluster_id, pdpb::CommandType::PutClusterConfig);
+    req.set_put_cluster_config(put_cluster_config);
+    Ok(req)
+}
+
+thread_local! {
+    static TRACE_ID: RefCell<Option<Uuid>> = RefCell::new(None);
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+    static CANCEL_TOKENS: RefCell<Vec<Arc<AtomicBool>>> = RefCell::new(vec![])
+}
+
+struct TraceIdGuard(Option<Uuid>);
+
+impl Drop for TraceIdGuard {
+    fn drop(&mut self) {
+        TRACE_ID.with(|t| *t.borrow_mut() = self.0.take());
+    }
+}
+
+/// `with_trace_id` runs `f`, all PD requests sent by `f` in the current thread carry
+/// `id` in their header instead of a random uuid, so they can be correlated in the
+/// logs of TiKV and PD.
+pub fn with_trace_id<T, F: FnOnce() -> T>(id: Uuid, f: F) -> T {
+    let prev = TRACE_ID.with(|t| t.borrow_mut().take());
+    let _guard = TraceIdGuard(prev);
+    TRACE_ID.with(|t| *t.borrow_mut() = Some(id));
+    f()
+}
+
+struct DeadlineGuard(Option<Instant>);
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.0));
+    }
+}
+
+/// `with_deadline` runs `f`, all PD requests sent by `f` in the current thread stop
+/// retrying and return `Timeout` once `deadline` passes, and a single attempt never
+/// waits beyond it. A nested deadline can't be later than the outer one.
+pub fn with_deadline<T, F: FnOnce() -> T>(deadline: Instant, f: F) -> T {
+    let prev = DEADLINE.with(|d| d.get());
+    let _guard = DeadlineGuard(prev);
+    let deadline = prev.map_or(deadline, |prev| cmp::min(prev, deadline));
+    DEADLINE.with(|d| d.set(Some(deadline)));
+    f()
+}
+
+// Returns the deadline set by `with_deadline` in the current thread.
+pub fn current_deadline() -> Option<Instant> {
+    DEADLINE.with(|d| d.get())
+}
+
+struct CancelGuard;
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        CANCEL_TOKENS.with(|t| t.borrow_mut().pop());
+    }
+}
+
+/// `with_cancel` runs `f`, all PD requests sent by `f` in the current thread return
+/// `Cancelled` as soon as `token` is set, even when they are waiting to retry. The
+/// tokens of nested calls all apply.
+pub fn with_cancel<T, F: FnOnce() -> T>(token: Arc<AtomicBool>, f: F) -> T {
+    CANCEL_TOKENS.with(|t| t.borrow_mut().push(token));
+    let _guard = CancelGuard;
+    f()
+}
+
+// Returns true if any token set by `with_cancel` in the current thread is set.
+pub fn is_cancelled() -> bool {
+    CANCEL_TOKENS.with(|t| t.borrow().iter().any(|token| token.load(Ordering::SeqCst)))
 }
 
 pub fn new_request(cluster_id: u64, cmd_type: pdpb::CommandType) -> pdpb::Request {
+    let id = TRACE_ID.with(|t| *t.borrow()).unwrap_or_else(Uuid::new_v4);
     let mut header = pdpb::RequestHeader::new();
     header.set_cluster_id(cluster_id);
-    header.set_uuid(Uuid::new_v4().as_bytes().to_vec());
+    header.set_uuid(id.as_bytes().to_vec());
     let mut req = pdpb::Request::new();
     req.set_header(header);
     req.set_cmd_type(cmd_type);
     req
 }
 
-fn check_resp(resp: &pdpb::Response) -> Result<()> {
+// Returns the uuid in the request header for logging.
+pub fn request_uuid(req: &pdpb::Request) -> String {
+    match Uuid::from_bytes(req.get_header().get_uuid()) {
+        Ok(id) => format!("{}", id),
+        Err(_) => format!("{:?}", req.get_header().get_uuid()),
+    }
+}
+
+// PD has no error type in the response header yet, so the only way to tell
+// that a request hits a PD which is not leader is the error message.
+const NOT_LEADER_ERR_MSG: &'static str = "not leader";
+const NOT_BOOTSTRAPPED_ERR_MSG: &'static str = "not bootstrapped";
+
+// Returns true if the response failed because of the PD node it is sent to,
+// so the same request may succeed after reconnecting to another PD node.
+// Other errors are caused by the request itself and retrying is useless.
+pub fn is_retriable_resp(resp: &pdpb::Response) -> bool {
+    if !resp.get_header().has_error() {
+        return false;
+    }
+    let error = resp.get_header().get_error();
+    !error.has_bootstrapped() && error.get_message().contains(NOT_LEADER_ERR_MSG)
+}
+
+// Checks the response header, a response from another cluster is always an error,
+// because it means the PD endpoint now serves a different cluster.
+pub fn check_resp(cluster_id: u64, resp: &pdpb::Response) -> Result<()> {
     if !resp.has_header() {
         return Err(box_err!("pd response missing header"));
     }
     let header = resp.get_header();
+    if header.get_cluster_id() != cluster_id {
+        return Err(Error::ClusterMismatch(cluster_id, header.get_cluster_id()));
+    }
     if !header.has_error() {
         return Ok(());
     }
@@ -198,7 +548,98 @@ fn check_resp(resp: &pdpb::Response) -> Result<()> {
     // TODO: translate more error types
     if error.has_bootstrapped() {
         Err(Error::ClusterBootstrapped(header.get_cluster_id()))
+    } else if error.get_message().contains(NOT_BOOTSTRAPPED_ERR_MSG) {
+        Err(Error::ClusterNotBootstrapped(header.get_cluster_id()))
     } else {
         Err(box_err!(error.get_message()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use kvproto::{metapb, pdpb};
+    use uuid::Uuid;
+
+    use super::{check_resp, is_bootstrap_region, is_retriable_resp, new_request, request_uuid,
+                with_trace_id};
+    use super::super::Error;
+
+    fn new_error_resp(msg: &str) -> pdpb::Response {
+        let mut resp = pdpb::Response::new();
+        resp.mut_header().mut_error().set_message(msg.to_owned());
+        resp
+    }
+
+    #[test]
+    fn test_retriable_resp() {
+        let resp = pdpb::Response::new();
+        assert!(!is_retriable_resp(&resp));
+        assert!(check_resp(0, &resp).is_err());
+
+        let resp = new_error_resp("pd is not leader");
+        assert!(is_retriable_resp(&resp));
+        assert!(check_resp(0, &resp).is_err());
+
+        let resp = new_error_resp("invalid region key");
+        assert!(!is_retriable_resp(&resp));
+        assert!(check_resp(0, &resp).is_err());
+
+        let mut resp = new_error_resp("not leader");
+        resp.mut_header().mut_error().mut_bootstrapped();
+        assert!(!is_retriable_resp(&resp));
+        match check_resp(0, &resp) {
+            Err(Error::ClusterBootstrapped(_)) => {}
+            other => panic!("expect bootstrapped error, got {:?}", other),
+        }
+
+        let mut resp = pdpb::Response::new();
+        resp.mut_header().set_cluster_id(1);
+        assert!(check_resp(1, &resp).is_ok());
+        match check_resp(2, &resp) {
+            Err(Error::ClusterMismatch(2, 1)) => {}
+            other => panic!("expect cluster mismatch error, got {:?}", other),
+        }
+
+        let resp = new_error_resp("cluster is not bootstrapped");
+        match check_resp(0, &resp) {
+            Err(Error::ClusterNotBootstrapped(0)) => {}
+            other => panic!("expect not bootstrapped error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_region() {
+        let mut region = metapb::Region::new();
+        region.set_id(2);
+        region.mut_region_epoch().set_version(1);
+        let epoch = region.get_region_epoch().clone();
+        assert!(!is_bootstrap_region(&region, 1, &epoch));
+
+        let mut peer = metapb::Peer::new();
+        peer.set_store_id(1);
+        region.mut_peers().push(peer);
+        assert!(is_bootstrap_region(&region, 1, &epoch));
+        assert!(!is_bootstrap_region(&region, 3, &epoch));
+
+        let mut other = epoch.clone();
+        other.set_conf_ver(2);
+        assert!(!is_bootstrap_region(&region, 1, &other));
+    }
+
+    #[test]
+    fn test_trace_id() {
+        let id = Uuid::new_v4();
+        let req = with_trace_id(id, || {
+            // Nested ids are restored when the inner one finishes.
+            let inner = with_trace_id(Uuid::new_v4(),
+                                      || new_request(1, pdpb::CommandType::AllocId));
+            assert!(inner.get_header().get_uuid() != id.as_bytes());
+            new_request(1, pdpb::CommandType::AllocId)
+        });
+        assert_eq!(req.get_header().get_uuid(), id.as_bytes());
+        assert_eq!(request_uuid(&req), format!("{}", id));
+
+        let req = new_request(1, pdpb::CommandType::AllocId);
+        assert!(req.get_header().get_uuid() != id.as_bytes());
+    }
+}
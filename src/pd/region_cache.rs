@@ -0,0 +1,172 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::collections::Bound::{Excluded, Unbounded};
+
+use kvproto::metapb;
+
+use util::HashMap;
+use raftstore::store::keys::{data_end_key, data_key};
+use super::{INVALID_ID, Key};
+
+// `RegionCache` keeps at most `capacity` regions fetched from PD,
+// the oldest region is evicted first when it is full.
+#[derive(Debug)]
+pub struct RegionCache {
+    capacity: usize,
+    next_seq: u64,
+    // region id -> (region, insertion sequence)
+    regions: HashMap<u64, (metapb::Region, u64)>,
+    // insertion sequence -> region id
+    order: BTreeMap<u64, u64>,
+    // encoded end key -> region id
+    region_ranges: BTreeMap<Key, u64>,
+}
+
+impl RegionCache {
+    pub fn new(capacity: usize) -> RegionCache {
+        RegionCache {
+            capacity: capacity,
+            next_seq: 0,
+            regions: HashMap::default(),
+            order: BTreeMap::new(),
+            region_ranges: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn get_region(&self, key: &[u8]) -> Option<metapb::Region> {
+        let (_, id) = match self.region_ranges
+            .range((Excluded(data_key(key)), Unbounded::<Key>))
+            .next() {
+            Some(item) => item,
+            None => return None,
+        };
+        let region = &self.regions[id].0;
+        if region.get_start_key() <= key {
+            Some(region.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn get_region_by_id(&self, region_id: u64) -> Option<metapb::Region> {
+        self.regions.get(&region_id).map(|&(ref region, _)| region.clone())
+    }
+
+    // Caches the region and removes all cached regions overlapping with it.
+    pub fn insert(&mut self, region: metapb::Region) {
+        if self.capacity == 0 || region.get_id() == INVALID_ID {
+            return;
+        }
+
+        self.invalidate(region.get_id());
+        let start_key = data_key(region.get_start_key());
+        let end_key = data_end_key(region.get_end_key());
+        let overlaps: Vec<u64> = self.region_ranges
+            .range((Excluded(start_key), Unbounded::<Key>))
+            .map(|(_, &id)| id)
+            .take_while(|id| data_key(self.regions[id].0.get_start_key()) < end_key)
+            .collect();
+        for id in overlaps {
+            self.invalidate(id);
+        }
+
+        while self.regions.len() >= self.capacity {
+            let oldest = *self.order.values().next().unwrap();
+            self.invalidate(oldest);
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert(seq, region.get_id());
+        self.region_ranges.insert(end_key, region.get_id());
+        self.regions.insert(region.get_id(), (region, seq));
+    }
+
+    pub fn invalidate(&mut self, region_id: u64) {
+        if let Some((region, seq)) = self.regions.remove(&region_id) {
+            self.order.remove(&seq);
+            self.region_ranges.remove(&data_end_key(region.get_end_key()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvproto::metapb;
+
+    use super::RegionCache;
+
+    fn new_region(id: u64, start_key: &[u8], end_key: &[u8]) -> metapb::Region {
+        let mut region = metapb::Region::new();
+        region.set_id(id);
+        region.set_start_key(start_key.to_vec());
+        region.set_end_key(end_key.to_vec());
+        region
+    }
+
+    #[test]
+    fn test_region_cache() {
+        let mut cache = RegionCache::new(3);
+        assert!(cache.get_region(b"a").is_none());
+
+        cache.insert(new_region(1, b"", b"b"));
+        cache.insert(new_region(2, b"b", b"d"));
+        assert_eq!(cache.get_region(b"").unwrap().get_id(), 1);
+        assert_eq!(cache.get_region(b"a").unwrap().get_id(), 1);
+        assert_eq!(cache.get_region(b"b").unwrap().get_id(), 2);
+        assert_eq!(cache.get_region_by_id(2).unwrap().get_start_key(), b"b");
+        // Miss, [d, +inf) is not cached.
+        assert!(cache.get_region(b"d").is_none());
+        assert!(cache.get_region_by_id(3).is_none());
+
+        cache.invalidate(2);
+        assert!(cache.get_region(b"b").is_none());
+        assert!(cache.get_region_by_id(2).is_none());
+        assert_eq!(cache.len(), 1);
+
+        // A split region replaces the overlapping ones.
+        cache.insert(new_region(2, b"b", b""));
+        cache.insert(new_region(3, b"c", b""));
+        assert!(cache.get_region(b"b").is_none());
+        assert_eq!(cache.len(), 2);
+        cache.insert(new_region(2, b"b", b"c"));
+        assert_eq!(cache.get_region(b"b").unwrap().get_id(), 2);
+        assert_eq!(cache.get_region(b"z").unwrap().get_id(), 3);
+        assert_eq!(cache.len(), 3);
+
+        // The oldest region is evicted.
+        let mut cache = RegionCache::new(2);
+        cache.insert(new_region(1, b"a", b"b"));
+        cache.insert(new_region(2, b"b", b"c"));
+        cache.insert(new_region(3, b"c", b"d"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_region_by_id(1).is_none());
+        assert!(cache.get_region(b"a").is_none());
+        assert_eq!(cache.get_region(b"c").unwrap().get_id(), 3);
+
+        let mut cache = RegionCache::new(0);
+        cache.insert(new_region(1, b"", b""));
+        assert!(cache.get_region(b"a").is_none());
+
+        // An empty region returned by PD is never cached.
+        let mut cache = RegionCache::new(1);
+        cache.insert(metapb::Region::new());
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use kvproto::{metapb, pdpb};
+
+use super::{INVALID_ID, Result};
+
+/// `StoreStatsBuilder` builds the `StoreStats` sent by `store_heartbeat`,
+/// fields which are not set are 0.
+pub struct StoreStatsBuilder {
+    stats: pdpb::StoreStats,
+}
+
+impl StoreStatsBuilder {
+    pub fn new(store_id: u64) -> StoreStatsBuilder {
+        let mut stats = pdpb::StoreStats::new();
+        stats.set_store_id(store_id);
+        StoreStatsBuilder { stats: stats }
+    }
+
+    /// Sets the disk capacity and the available space of the store in bytes.
+    pub fn space(mut self, capacity: u64, available: u64) -> StoreStatsBuilder {
+        self.stats.set_capacity(capacity);
+        self.stats.set_available(available);
+        self
+    }
+
+    pub fn region_count(mut self, count: u32) -> StoreStatsBuilder {
+        self.stats.set_region_count(count);
+        self
+    }
+
+    /// Sets the number of snapshots being sent, received and applied.
+    pub fn snap_count(mut self, sending: u32, receiving: u32, applying: u32) -> StoreStatsBuilder {
+        self.stats.set_sending_snap_count(sending);
+        self.stats.set_receiving_snap_count(receiving);
+        self.stats.set_applying_snap_count(applying);
+        self
+    }
+
+    /// Sets when the store starts, in seconds since the epoch.
+    pub fn start_time(mut self, secs: u32) -> StoreStatsBuilder {
+        self.stats.set_start_time(secs);
+        self
+    }
+
+    pub fn busy(mut self, busy: bool) -> StoreStatsBuilder {
+        self.stats.set_is_busy(busy);
+        self
+    }
+
+    pub fn build(self) -> Result<pdpb::StoreStats> {
+        if self.stats.get_store_id() == INVALID_ID {
+            return Err(box_err!("invalid store id {}", INVALID_ID));
+        }
+        if self.stats.get_available() > self.stats.get_capacity() {
+            return Err(box_err!("available space {} is larger than capacity {}",
+                                self.stats.get_available(),
+                                self.stats.get_capacity()));
+        }
+        Ok(self.stats)
+    }
+}
+
+/// `new_peer_stats` builds the stats of a down peer passed to `region_heartbeat`,
+/// `down_duration` is how long the leader hasn't heard from the peer, in whole seconds.
+pub fn new_peer_stats(peer: metapb::Peer, down_duration: Duration) -> pdpb::PeerStats {
+    let mut stats = pdpb::PeerStats::new();
+    stats.set_peer(peer);
+    stats.set_down_seconds(down_duration.as_secs());
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use kvproto::metapb;
+
+    use super::{StoreStatsBuilder, new_peer_stats};
+
+    #[test]
+    fn test_store_stats_builder() {
+        let stats = StoreStatsBuilder::new(1)
+            .space(100, 40)
+            .region_count(3)
+            .snap_count(1, 2, 3)
+            .start_time(1000)
+            .busy(true)
+            .build()
+            .unwrap();
+        assert_eq!(stats.get_store_id(), 1);
+        assert_eq!(stats.get_capacity(), 100);
+        assert_eq!(stats.get_available(), 40);
+        assert_eq!(stats.get_region_count(), 3);
+        assert_eq!(stats.get_sending_snap_count(), 1);
+        assert_eq!(stats.get_receiving_snap_count(), 2);
+        assert_eq!(stats.get_applying_snap_count(), 3);
+        assert_eq!(stats.get_start_time(), 1000);
+        assert!(stats.get_is_busy());
+
+        // A full disk is fine.
+        assert!(StoreStatsBuilder::new(1).space(100, 0).build().is_ok());
+
+        assert!(StoreStatsBuilder::new(0).build().is_err());
+        assert!(StoreStatsBuilder::new(1).space(100, 101).build().is_err());
+    }
+
+    #[test]
+    fn test_new_peer_stats() {
+        let mut peer = metapb::Peer::new();
+        peer.set_id(2);
+        peer.set_store_id(3);
+        let stats = new_peer_stats(peer.clone(), Duration::from_millis(90500));
+        assert_eq!(stats.get_peer(), &peer);
+        assert_eq!(stats.get_down_seconds(), 90);
+    }
+}
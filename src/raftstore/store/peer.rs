@@ -43,7 +43,7 @@ use util::worker::{Worker, Scheduler};
 use raftstore::store::worker::{ApplyTask, ApplyRes};
 use util::{clocktime, Either, HashMap, HashSet};
 
-use pd::INVALID_ID;
+use pd::{INVALID_ID, new_peer_stats};
 
 use super::store::Store;
 use super::peer_storage::{PeerStorage, ApplySnapResult, write_peer_state, InvokeContext};
@@ -515,11 +515,9 @@ impl Peer {
                 continue;
             }
             if let Some(instant) = self.peer_heartbeats.get(&p.get_id()) {
-                if instant.elapsed() >= max_duration {
-                    let mut stats = PeerStats::new();
-                    stats.set_peer(p.clone());
-                    stats.set_down_seconds(instant.elapsed().as_secs());
-                    down_peers.push(stats);
+                let elapsed = instant.elapsed();
+                if elapsed >= max_duration {
+                    down_peers.push(new_peer_stats(p.clone(), elapsed));
                 }
             }
         }
@@ -32,9 +32,8 @@ use time::{self, Timespec};
 use kvproto::raft_serverpb::{RaftMessage, RaftSnapshotData, RaftTruncatedState, RegionLocalState,
                              PeerState};
 use kvproto::eraftpb::{ConfChangeType, MessageType};
-use kvproto::pdpb::StoreStats;
 use util::{SlowTimer, duration_to_sec, escape};
-use pd::PdClient;
+use pd::{PdClient, StoreStatsBuilder};
 use kvproto::raft_cmdpb::{AdminCmdType, AdminRequest, StatusCmdType, StatusResponse,
                           RaftCmdRequest, RaftCmdResponse};
 use protobuf::Message;
@@ -1527,7 +1526,6 @@ impl<T: Transport, C: PdClient> Store<T, C> {
     }
 
     fn store_heartbeat_pd(&mut self) {
-        let mut stats = StoreStats::new();
         let disk_stats = match fs2::statvfs(self.engine.path()) {
             Err(e) => {
                 error!("{} get disk stat for rocksdb {} failed: {}",
@@ -1545,7 +1543,6 @@ impl<T: Transport, C: PdClient> Store<T, C> {
         } else {
             self.cfg.capacity
         };
-        stats.set_capacity(capacity);
 
         let mut used_size = flush_engine_properties_and_get_used_size(self.engine.clone());
         used_size += self.snap_mgr.get_total_snap_size();
@@ -1563,13 +1560,7 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             available = disk_stats.free_space();
         }
 
-        stats.set_store_id(self.store_id());
-        stats.set_available(available);
-        stats.set_region_count(self.region_peers.len() as u32);
-
         let snap_stats = self.snap_mgr.stats();
-        stats.set_sending_snap_count(snap_stats.sending_count as u32);
-        stats.set_receiving_snap_count(snap_stats.receiving_count as u32);
 
         STORE_SIZE_GAUGE_VEC.with_label_values(&["capacity"]).set(capacity as f64);
         STORE_SIZE_GAUGE_VEC.with_label_values(&["available"]).set(available as f64);
@@ -1586,14 +1577,26 @@ impl<T: Transport, C: PdClient> Store<T, C> {
             }
         }
 
-        stats.set_applying_snap_count(apply_snapshot_count as u32);
         STORE_SNAPSHOT_TRAFFIC_GAUGE_VEC.with_label_values(&["applying"])
             .set(apply_snapshot_count as f64);
 
-        stats.set_start_time(self.start_time.sec as u32);
-
-        stats.set_is_busy(self.is_busy);
+        let res = StoreStatsBuilder::new(self.store_id())
+            .space(capacity, available)
+            .region_count(self.region_peers.len() as u32)
+            .snap_count(snap_stats.sending_count as u32,
+                        snap_stats.receiving_count as u32,
+                        apply_snapshot_count as u32)
+            .start_time(self.start_time.sec as u32)
+            .busy(self.is_busy)
+            .build();
         self.is_busy = false;
+        let stats = match res {
+            Ok(stats) => stats,
+            Err(e) => {
+                error!("{} invalid store stats: {:?}", self.tag, e);
+                return;
+            }
+        };
 
         if let Err(e) = self.pd_worker.schedule(PdTask::StoreHeartbeat { stats: stats }) {
             error!("{} failed to notify pd: {}", self.tag, e);
@@ -25,7 +25,7 @@ use kvproto::pdpb;
 use util::worker::Runnable;
 use util::escape;
 use util::transport::SendCh;
-use pd::PdClient;
+use pd::{HeartbeatDirective, PdClient};
 use raftstore::store::Msg;
 use raftstore::store::util::is_epoch_stale;
 
@@ -148,31 +148,32 @@ impl<T: PdClient> Runner<T> {
         // Now we use put region protocol for heartbeat.
         match self.pd_client
             .region_heartbeat(region.clone(), peer.clone(), down_peers, pending_peers) {
-            Ok(mut resp) => {
+            Ok(resp) => {
                 PD_REQ_COUNTER_VEC.with_label_values(&["heartbeat", "success"]).inc();
 
-                if resp.has_change_peer() {
-                    PD_HEARTBEAT_COUNTER_VEC.with_label_values(&["change peer"]).inc();
-
-                    let mut change_peer = resp.take_change_peer();
-                    info!("[region {}] try to change peer {:?} {:?} for region {:?}",
-                          region.get_id(),
-                          change_peer.get_change_type(),
-                          change_peer.get_peer(),
-                          region);
-                    let req = new_change_peer_request(change_peer.get_change_type(),
-                                                      change_peer.take_peer());
-                    self.send_admin_request(region, peer, req);
-                } else if resp.has_transfer_leader() {
-                    PD_HEARTBEAT_COUNTER_VEC.with_label_values(&["transfer leader"]).inc();
-
-                    let mut transfer_leader = resp.take_transfer_leader();
-                    info!("[region {}] try to transfer leader from {:?} to {:?}",
-                          region.get_id(),
-                          peer,
-                          transfer_leader.get_peer());
-                    let req = new_transfer_leader_request(transfer_leader.take_peer());
-                    self.send_admin_request(region, peer, req)
+                match HeartbeatDirective::from_resp(&resp) {
+                    HeartbeatDirective::ChangePeer(change_type, change_peer) => {
+                        PD_HEARTBEAT_COUNTER_VEC.with_label_values(&["change peer"]).inc();
+
+                        info!("[region {}] try to change peer {:?} {:?} for region {:?}",
+                              region.get_id(),
+                              change_type,
+                              change_peer,
+                              region);
+                        let req = new_change_peer_request(change_type, change_peer);
+                        self.send_admin_request(region, peer, req);
+                    }
+                    HeartbeatDirective::TransferLeader(leader) => {
+                        PD_HEARTBEAT_COUNTER_VEC.with_label_values(&["transfer leader"]).inc();
+
+                        info!("[region {}] try to transfer leader from {:?} to {:?}",
+                              region.get_id(),
+                              peer,
+                              leader);
+                        let req = new_transfer_leader_request(leader);
+                        self.send_admin_request(region, peer, req)
+                    }
+                    HeartbeatDirective::Noop => {}
                 }
             }
             Err(e) => {
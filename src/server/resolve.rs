@@ -179,6 +179,9 @@ mod tests {
         fn alloc_id(&self) -> Result<u64> {
             unimplemented!();
         }
+        fn get_tso(&self) -> Result<u64> {
+            unimplemented!();
+        }
         fn put_store(&self, _: metapb::Store) -> Result<()> {
             unimplemented!();
         }
@@ -193,6 +196,9 @@ mod tests {
         fn get_cluster_config(&self) -> Result<metapb::Cluster> {
             unimplemented!();
         }
+        fn put_cluster_config(&self, _: metapb::Cluster) -> Result<()> {
+            unimplemented!();
+        }
         fn get_region(&self, _: &[u8]) -> Result<metapb::Region> {
             unimplemented!();
         }
@@ -210,7 +216,7 @@ mod tests {
         fn ask_split(&self, _: metapb::Region) -> Result<pdpb::AskSplitResponse> {
             unimplemented!();
         }
-        fn store_heartbeat(&self, _: pdpb::StoreStats) -> Result<()> {
+        fn store_heartbeat(&self, _: pdpb::StoreStats) -> Result<pdpb::StoreHeartbeatResponse> {
             unimplemented!();
         }
         fn report_split(&self, _: metapb::Region, _: metapb::Region) -> Result<()> {
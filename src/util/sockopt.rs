@@ -12,20 +12,33 @@
 // limitations under the License.
 
 use std::io::Result;
+use std::time::Duration;
 
 pub trait SocketOpt {
     fn set_send_buffer_size(&self, _size: usize) -> Result<()>;
     fn send_buffer_size(&self) -> Result<usize>;
     fn set_recv_buffer_size(&self, _size: usize) -> Result<()>;
     fn recv_buffer_size(&self) -> Result<usize>;
+    // Enables TCP keepalive which starts probing after the connection is idle for
+    // `time`, and drops the connection if the peer doesn't answer within `timeout`.
+    // `None` disables keepalive.
+    fn set_keepalive(&self, _time: Option<Duration>, _timeout: Duration) -> Result<()>;
 }
 
 #[cfg(unix)]
 mod unix {
     use util::sockopt::SocketOpt;
 
-    use std::io::{Result, Error};
-    use std::os::unix::io::AsRawFd;
+    use std::{cmp, mem};
+    use std::io::{Result, Error, ErrorKind};
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+    use std::time::{Duration, Instant};
+
+    use libc::{self, c_int, c_void, socklen_t};
+    use mio::tcp::TcpStream as MioTcpStream;
+
+    use util::duration_to_ms;
 
     use nix::Error as NixError;
     use nix::sys::socket;
@@ -47,6 +60,110 @@ mod unix {
         fn recv_buffer_size(&self) -> Result<usize> {
             socket::getsockopt(self.as_raw_fd(), sockopt::RcvBuf).map_err(from_nix_error)
         }
+
+        fn set_keepalive(&self, time: Option<Duration>, timeout: Duration) -> Result<()> {
+            let fd = self.as_raw_fd();
+            let time = match time {
+                None => return setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 0),
+                Some(time) => time,
+            };
+            try!(setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1));
+            set_keepalive_probes(fd, time, timeout)
+        }
+    }
+
+    const KEEPALIVE_PROBES: c_int = 3;
+
+    #[cfg(target_os = "linux")]
+    fn set_keepalive_probes(fd: RawFd, time: Duration, timeout: Duration) -> Result<()> {
+        use std::cmp;
+
+        // The kernel only accepts whole seconds, and at least one.
+        let idle = cmp::max(time.as_secs(), 1) as c_int;
+        let interval = cmp::max(timeout.as_secs() / KEEPALIVE_PROBES as u64, 1) as c_int;
+        try!(setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle));
+        try!(setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval));
+        setsockopt_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, KEEPALIVE_PROBES)
+    }
+
+    // Other systems probe with their default settings.
+    #[cfg(not(target_os = "linux"))]
+    fn set_keepalive_probes(_: RawFd, _: Duration, _: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    /// Connects to `addr` without blocking the thread beyond `timeout`, which the
+    /// kernel may otherwise do for minutes if the address is blackholed.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<TcpStream> {
+        // mio starts connecting without blocking.
+        let stream = try!(MioTcpStream::connect(addr));
+        let mut pfd = libc::pollfd {
+            fd: stream.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut,
+                                      format!("connecting to {} takes more than {:?}",
+                                              addr,
+                                              timeout)));
+            }
+            // Round up, so it never polls with 0 which returns at once.
+            let ms = cmp::min(duration_to_ms(deadline - now) + 1, c_int::max_value() as u64);
+            let ret = unsafe { libc::poll(&mut pfd, 1, ms as c_int) };
+            if ret > 0 {
+                break;
+            }
+            if ret < 0 {
+                let e = Error::last_os_error();
+                if e.kind() != ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+        // The socket is writable once connecting is done, whether it fails or not.
+        let err = try!(getsockopt_int(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR));
+        if err != 0 {
+            return Err(Error::from_raw_os_error(err));
+        }
+
+        let stream = unsafe { TcpStream::from_raw_fd(stream.into_raw_fd()) };
+        try!(stream.set_nonblocking(false));
+        try!(stream.set_nodelay(true));
+        Ok(stream)
+    }
+
+    fn getsockopt_int(fd: RawFd, level: c_int, name: c_int) -> Result<c_int> {
+        let mut value: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(fd,
+                             level,
+                             name,
+                             &mut value as *mut c_int as *mut c_void,
+                             &mut len)
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    fn setsockopt_int(fd: RawFd, level: c_int, name: c_int, value: c_int) -> Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(fd,
+                             level,
+                             name,
+                             &value as *const c_int as *const c_void,
+                             mem::size_of::<c_int>() as socklen_t)
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
     }
 
     fn from_nix_error(err: NixError) -> Error {
@@ -56,10 +173,11 @@ mod unix {
     #[cfg(test)]
     mod tests {
         use std::os::unix::io::AsRawFd;
+        use std::time::Duration;
         use std::net::{SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream};
         use mio::tcp::{TcpListener as MioTcpListener, TcpStream as MioTcpStream};
 
-        use util::sockopt::SocketOpt;
+        use util::sockopt::{SocketOpt, connect_timeout};
 
         #[cfg(unix)]
         fn test_sock_opt<T: AsRawFd>(socket: &T) {
@@ -77,6 +195,9 @@ mod unix {
             socket.set_recv_buffer_size(8192).unwrap();
             let mio_r2 = socket.recv_buffer_size().unwrap();
             assert!(mio_r2 != mio_r1, "{} should not equal {}", mio_r2, mio_r1);
+
+            socket.set_keepalive(Some(Duration::from_secs(10)), Duration::from_secs(3)).unwrap();
+            socket.set_keepalive(None, Duration::from_secs(3)).unwrap();
         }
 
         #[cfg(unix)]
@@ -100,6 +221,20 @@ mod unix {
 
             test_sock_opt(&std_sock);
         }
+
+        #[test]
+        fn test_connect_timeout() {
+            let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let stream = connect_timeout(&addr, Duration::from_secs(3)).unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), addr);
+            assert!(stream.nodelay().unwrap());
+
+            // Nothing listens on it any more.
+            drop(listener);
+            drop(stream);
+            assert!(connect_timeout(&addr, Duration::from_secs(3)).is_err());
+        }
     }
 }
 
@@ -110,6 +245,16 @@ mod windows {
 
     use mio::tcp::TcpStream;
     use std::io::Result;
+    use std::net::{SocketAddr, TcpStream as StdTcpStream};
+    use std::time::Duration;
+
+    // Connecting is not bounded by `timeout` here yet, it blocks until the system
+    // gives up.
+    pub fn connect_timeout(addr: &SocketAddr, _timeout: Duration) -> Result<StdTcpStream> {
+        let stream = try!(StdTcpStream::connect(addr));
+        try!(stream.set_nodelay(true));
+        Ok(stream)
+    }
 
     impl SocketOpt for TcpStream {
         fn set_send_buffer_size(&self, _size: usize) -> Result<()> {
@@ -131,6 +276,11 @@ mod windows {
             error!("recv_buffer_size is not supported in windows now");
             Ok(0)
         }
+
+        fn set_keepalive(&self, _time: Option<Duration>, _timeout: Duration) -> Result<()> {
+            error!("set_keepalive is not supported in windows now");
+            Ok(())
+        }
     }
 }
 
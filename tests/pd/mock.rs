@@ -0,0 +1,301 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use kvproto::pdpb;
+use kvproto::msgpb::{Message, MessageType};
+
+use tikv::pd::RpcClientBuilder;
+use tikv::util::HandyRwLock;
+use tikv::util::codec::rpc;
+
+pub type Handler = Box<Fn(&pdpb::Request) -> pdpb::Response + Send + Sync>;
+
+// How many bytes of the next response are written before stalling, and for how long.
+type Stall = Arc<Mutex<Option<(usize, Duration)>>>;
+
+// A fake PD which speaks the PD RPC protocol on a local port,
+// every request is answered by the handler.
+pub struct MockPd {
+    endpoint: String,
+    handler: Arc<RwLock<Handler>>,
+    stopped: Arc<AtomicBool>,
+    stall: Stall,
+}
+
+impl MockPd {
+    // Creates a PD which fails all requests until a handler is set.
+    pub fn new() -> MockPd {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("{}", listener.local_addr().unwrap());
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handler: Handler =
+            box |req: &pdpb::Request| new_error_resp(0, req.get_cmd_type(), "no handler");
+        let handler = Arc::new(RwLock::new(handler));
+
+        let stall = Arc::new(Mutex::new(None));
+        let handler2 = handler.clone();
+        let stopped2 = stopped.clone();
+        let stall2 = stall.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped2.load(Ordering::SeqCst) {
+                    return;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let handler = handler2.clone();
+                let stopped = stopped2.clone();
+                let stall = stall2.clone();
+                thread::spawn(move || serve(stream, handler, stopped, stall));
+            }
+        });
+
+        MockPd {
+            endpoint: endpoint,
+            handler: handler,
+            stopped: stopped,
+            stall: stall,
+        }
+    }
+
+    pub fn set_handler<F>(&self, handler: F)
+        where F: Fn(&pdpb::Request) -> pdpb::Response + Send + Sync + 'static
+    {
+        *self.handler.wl() = box handler;
+    }
+
+    // Answers GetPDMembers of cluster 1 with `urls`, the first one is the leader, and
+    // the other requests with `handler`. A request it returns None for is unsupported.
+    pub fn handle_with<F>(&self, urls: Vec<String>, handler: F)
+        where F: Fn(pdpb::CommandType, &pdpb::Request) -> Option<pdpb::Response>
+                     + Send
+                     + Sync
+                     + 'static
+    {
+        self.set_handler(move |req| {
+            let cmd = req.get_cmd_type();
+            if cmd == pdpb::CommandType::GetPDMembers {
+                return new_members_resp(1, &urls);
+            }
+            handler(cmd, req).unwrap_or_else(|| new_error_resp(1, cmd, "unsupported"))
+        });
+    }
+
+    // Returns a builder of the client which connects to this PD.
+    pub fn builder(&self) -> RpcClientBuilder {
+        RpcClientBuilder::new().endpoints(&self.endpoint)
+    }
+
+    // Writes only the first `bytes` bytes of the next response, and the rest after
+    // `duration`, like a PD which stalls in the middle of a frame.
+    pub fn stall_next_response(&self, bytes: usize, duration: Duration) {
+        *self.stall.lock().unwrap() = Some((bytes, duration));
+    }
+
+    pub fn endpoint(&self) -> String {
+        self.endpoint.clone()
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.endpoint)
+    }
+
+    // Stop serving, all later requests and connections fail.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accepting thread.
+        let _ = TcpStream::connect(self.endpoint.as_str());
+    }
+}
+
+impl Drop for MockPd {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn serve(mut stream: TcpStream,
+         handler: Arc<RwLock<Handler>>,
+         stopped: Arc<AtomicBool>,
+         stall: Stall) {
+    // Skip the HTTP header which asks PD to hijack the connection.
+    if read_http_header(&mut stream).is_none() {
+        return;
+    }
+
+    loop {
+        let mut req = Message::new();
+        let msg_id = match rpc::decode_msg(&mut stream, &mut req) {
+            Ok(msg_id) => msg_id,
+            Err(_) => return,
+        };
+        if stopped.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut resp = Message::new();
+        resp.set_msg_type(MessageType::PdResp);
+        resp.set_pd_resp((*handler.rl())(req.get_pd_req()));
+        let mut frame = vec![];
+        rpc::encode_msg(&mut frame, msg_id, &resp).unwrap();
+        let mut written = 0;
+        if let Some((bytes, duration)) = stall.lock().unwrap().take() {
+            written = cmp::min(bytes, frame.len());
+            if stream.write_all(&frame[..written]).is_err() {
+                return;
+            }
+            thread::sleep(duration);
+        }
+        if stream.write_all(&frame[written..]).is_err() {
+            return;
+        }
+    }
+}
+
+// Creates a PD of cluster 1 which is the only member, see `MockPd::handle_with`.
+pub fn mock_with<F>(handler: F) -> MockPd
+    where F: Fn(pdpb::CommandType, &pdpb::Request) -> Option<pdpb::Response>
+                 + Send
+                 + Sync
+                 + 'static
+{
+    let pd = MockPd::new();
+    pd.handle_with(vec![pd.url()], handler);
+    pd
+}
+
+pub fn new_resp(cluster_id: u64, cmd_type: pdpb::CommandType) -> pdpb::Response {
+    let mut resp = pdpb::Response::new();
+    resp.mut_header().set_cluster_id(cluster_id);
+    resp.set_cmd_type(cmd_type);
+    resp
+}
+
+pub fn new_error_resp(cluster_id: u64, cmd_type: pdpb::CommandType, msg: &str) -> pdpb::Response {
+    let mut resp = new_resp(cluster_id, cmd_type);
+    resp.mut_header().mut_error().set_message(msg.to_owned());
+    resp
+}
+
+pub fn new_member(name: &str, url: &str) -> pdpb::PDMember {
+    let mut member = pdpb::PDMember::new();
+    member.set_name(name.to_owned());
+    member.mut_client_urls().push(url.to_owned());
+    member
+}
+
+// Builds a GetPDMembers response with the given members, the first one is the leader.
+pub fn new_members_resp(cluster_id: u64, urls: &[String]) -> pdpb::Response {
+    let mut resp = new_resp(cluster_id, pdpb::CommandType::GetPDMembers);
+    for (i, url) in urls.iter().enumerate() {
+        let member = new_member(&format!("pd{}", i), url);
+        if i == 0 {
+            resp.mut_get_pd_members().set_leader(member.clone());
+        }
+        resp.mut_get_pd_members().mut_members().push(member);
+    }
+    resp
+}
+
+// Reads a HTTP header byte by byte, returns None if the connection is closed.
+fn read_http_header(stream: &mut TcpStream) -> Option<String> {
+    let mut header = vec![];
+    let mut b = [0; 1];
+    while !header.ends_with(b"\r\n\r\n") {
+        if stream.read_exact(&mut b).is_err() {
+            return None;
+        }
+        header.push(b[0]);
+    }
+    Some(String::from_utf8_lossy(&header).into_owned())
+}
+
+// A HTTP proxy which only supports the CONNECT method.
+pub struct MockProxy {
+    endpoint: String,
+    tunnels: Arc<AtomicUsize>,
+}
+
+impl MockProxy {
+    // Creates a proxy which answers CONNECT with `status`, like `200 OK`.
+    pub fn new(status: &'static str) -> MockProxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("{}", listener.local_addr().unwrap());
+        let tunnels = Arc::new(AtomicUsize::new(0));
+        let tunnels2 = tunnels.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let tunnels = tunnels2.clone();
+                thread::spawn(move || tunnel(stream, status, tunnels));
+            }
+        });
+
+        MockProxy {
+            endpoint: endpoint,
+            tunnels: tunnels,
+        }
+    }
+
+    pub fn endpoint(&self) -> String {
+        self.endpoint.clone()
+    }
+
+    // Returns how many tunnels have been opened.
+    pub fn tunnels(&self) -> usize {
+        self.tunnels.load(Ordering::SeqCst)
+    }
+}
+
+fn tunnel(mut client: TcpStream, status: &str, tunnels: Arc<AtomicUsize>) {
+    let header = match read_http_header(&mut client) {
+        Some(header) => header,
+        None => return,
+    };
+    // Like `CONNECT 127.0.0.1:2379 HTTP/1.1`.
+    let target = header.split_whitespace().nth(1).unwrap_or("").to_owned();
+    if !status.starts_with("200") {
+        let _ = client.write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes());
+        return;
+    }
+    let mut server = match TcpStream::connect(target.as_str()) {
+        Ok(server) => server,
+        Err(_) => return,
+    };
+    tunnels.fetch_add(1, Ordering::SeqCst);
+    if client.write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes()).is_err() {
+        return;
+    }
+
+    let mut client2 = client.try_clone().unwrap();
+    let mut server2 = server.try_clone().unwrap();
+    thread::spawn(move || {
+        let _ = io::copy(&mut server2, &mut client2);
+        let _ = client2.shutdown(Shutdown::Both);
+    });
+    let _ = io::copy(&mut client, &mut server);
+    let _ = server.shutdown(Shutdown::Both);
+}
@@ -11,4 +11,6 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod mock;
+
 mod test_rpc_client;
@@ -12,10 +12,21 @@
 // limitations under the License.
 
 use std::env;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use kvproto::metapb;
+use kvproto::{metapb, pdpb};
+use prometheus;
+use uuid::Uuid;
 
-use tikv::pd::{PdClient, RpcClient};
+use tikv::pd::{EndpointStrategy, Error, OverloadPolicy, PdClient, ReconnectPolicy, RpcClient,
+               RpcClientBuilder, StoreStatsBuilder, compose_ts, new_peer_stats, rpc_connect,
+               with_cancel, with_deadline, with_trace_id};
+
+use super::mock::*;
 
 #[test]
 fn test_rpc_client() {
@@ -77,3 +88,2139 @@ fn test_rpc_client_safely_new() {
 
     assert!(RpcClient::validate_endpoints(&endpoints).is_err());
 }
+
+#[test]
+fn test_rpc_client_skip_invalid_endpoints() {
+    let endpoints = match env::var("PD_ENDPOINTS") {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    // Malformed endpoints must be skipped rather than panicking the client.
+    let endpoints = format!("pd0,pd0:abc,{}", endpoints);
+    let client = RpcClient::new(&endpoints).unwrap();
+    assert_eq!(client.cluster_id, client.get_cluster_id().unwrap());
+}
+
+fn new_alloc_id_resp(cluster_id: u64, id: u64) -> pdpb::Response {
+    let mut resp = new_resp(cluster_id, pdpb::CommandType::AllocId);
+    resp.mut_alloc_id().set_id(id);
+    resp
+}
+
+// Sets up PD members, the first one is the leader and all of them can alloc ids.
+fn setup_members(members: &[MockPd], cluster_id: u64) {
+    let urls: Vec<String> = members.iter().map(|pd| pd.url()).collect();
+    for (i, pd) in members.iter().enumerate() {
+        let urls = urls.clone();
+        pd.set_handler(move |req| {
+            match req.get_cmd_type() {
+                pdpb::CommandType::GetPDMembers => new_members_resp(cluster_id, &urls),
+                pdpb::CommandType::AllocId => new_alloc_id_resp(cluster_id, i as u64 + 1),
+                cmd => new_error_resp(cluster_id, cmd, "unsupported"),
+            }
+        });
+    }
+}
+
+// Sums all series of the counter `name` in the default registry.
+fn counter_value(name: &str) -> f64 {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric().iter())
+        .map(|metric| metric.get_counter().get_value())
+        .fold(0.0, |sum, v| sum + v)
+}
+
+// Returns the value of the counter `name` whose label value is `label`.
+fn labeled_counter_value(name: &str, label: &str) -> f64 {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric().iter())
+        .filter(|metric| metric.get_label().iter().any(|pair| pair.get_value() == label))
+        .map(|metric| metric.get_counter().get_value())
+        .fold(0.0, |sum, v| sum + v)
+}
+
+// Waits until `count` reaches `n`, returns false if it doesn't in a few seconds, so
+// a test which expects concurrent requests fails rather than hangs without them.
+fn wait_count(count: &AtomicUsize, n: usize) -> bool {
+    for _ in 0..500 {
+        if count.load(Ordering::SeqCst) >= n {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+// Returns the label values of all series of the counter `name`.
+fn counter_labels(name: &str) -> Vec<String> {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric().iter())
+        .flat_map(|metric| metric.get_label().iter())
+        .map(|pair| pair.get_value().to_owned())
+        .collect()
+}
+
+fn histogram_sample_count(name: &str) -> u64 {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric().iter())
+        .map(|metric| metric.get_histogram().get_sample_count())
+        .fold(0, |sum, v| sum + v)
+}
+
+#[test]
+fn test_rpc_client_leader() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+
+    let endpoints = format!("{},{}", members[1].endpoint(), members[0].endpoint());
+    let client = RpcClient::new(&endpoints).unwrap();
+    let leader = client.get_leader().unwrap();
+    assert_eq!(leader.get_client_urls().to_vec(), vec![members[0].url()]);
+    assert_eq!(client.current_endpoint(), None);
+
+    // The leader is always connected first.
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(client.current_endpoint(), Some(members[0].endpoint()));
+}
+
+#[test]
+fn test_rpc_client_refresh_members() {
+    let members = vec![MockPd::new(), MockPd::new(), MockPd::new()];
+    setup_members(&members[..2], 1);
+
+    let client = RpcClient::new(&members[0].endpoint()).unwrap();
+    client.set_members_refresh_interval(Duration::from_secs(0));
+    assert_eq!(client.alloc_id().unwrap(), 1);
+
+    // A new member becomes the leader and the old leader is removed.
+    let urls = vec![members[2].url(), members[1].url()];
+    for pd in &members {
+        pd.handle_with(urls.clone(), move |cmd, _| {
+            match cmd {
+                pdpb::CommandType::AllocId => Some(new_alloc_id_resp(1, 3)),
+                _ => None,
+            }
+        });
+    }
+    members[0].stop();
+
+    let retries = counter_value("tikv_pd_request_retry_total");
+    let leader_changes = counter_value("tikv_pd_leader_change_total");
+    assert_eq!(client.alloc_id().unwrap(), 3);
+    assert_eq!(client.current_endpoint(), Some(members[2].endpoint()));
+    assert!(counter_value("tikv_pd_request_retry_total") > retries);
+    assert!(counter_value("tikv_pd_leader_change_total") > leader_changes);
+    assert_eq!(client.get_leader().unwrap().get_client_urls().to_vec(),
+               vec![members[2].url()]);
+}
+
+#[test]
+fn test_rpc_client_get_tso() {
+    let logical = Arc::new(AtomicUsize::new(0));
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            pdpb::CommandType::Tso => {
+                assert_eq!(req.get_tso().get_number(), 1);
+                let mut ts = pdpb::Timestamp::new();
+                ts.set_physical(1000);
+                ts.set_logical(logical.fetch_add(1, Ordering::SeqCst) as i64);
+                let mut resp = new_resp(1, pdpb::CommandType::Tso);
+                resp.mut_tso().mut_timestamps().push(ts);
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    let mut prev_ts = 0;
+    for i in 0..10 {
+        let ts = client.get_tso().unwrap();
+        assert_eq!(ts, compose_ts(1000, i));
+        assert!(ts > prev_ts);
+        prev_ts = ts;
+    }
+}
+
+#[test]
+fn test_rpc_client_store_heartbeat() {
+    let sent = Arc::new(Mutex::new(None));
+    let sent2 = sent.clone();
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            pdpb::CommandType::StoreHeartbeat => {
+                *sent2.lock().unwrap() = Some(req.get_store_heartbeat().get_stats().clone());
+                let mut resp = new_resp(1, pdpb::CommandType::StoreHeartbeat);
+                resp.set_store_heartbeat(pdpb::StoreHeartbeatResponse::new());
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    let stats = StoreStatsBuilder::new(2)
+        .space(100, 40)
+        .region_count(3)
+        .snap_count(1, 2, 3)
+        .start_time(1000)
+        .busy(true)
+        .build()
+        .unwrap();
+    let resp = client.store_heartbeat(stats.clone()).unwrap();
+    assert_eq!(resp, pdpb::StoreHeartbeatResponse::new());
+    // The built stats are sent as they are.
+    assert_eq!(sent.lock().unwrap().take(), Some(stats));
+}
+
+#[test]
+fn test_rpc_client_cluster_config() {
+    let mut cluster = metapb::Cluster::new();
+    cluster.set_id(1);
+    cluster.set_max_peer_count(3);
+    let config = Arc::new(Mutex::new(cluster));
+    let config2 = config.clone();
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            pdpb::CommandType::GetClusterConfig => {
+                let mut resp = new_resp(1, pdpb::CommandType::GetClusterConfig);
+                resp.mut_get_cluster_config().set_cluster(config2.lock().unwrap().clone());
+                Some(resp)
+            }
+            pdpb::CommandType::PutClusterConfig => {
+                *config2.lock().unwrap() = req.get_put_cluster_config().get_cluster().clone();
+                Some(new_resp(1, pdpb::CommandType::PutClusterConfig))
+            }
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    let mut cluster = client.get_cluster_config().unwrap();
+    assert_eq!(cluster.get_max_peer_count(), 3);
+    cluster.set_max_peer_count(5);
+    client.put_cluster_config(cluster.clone()).unwrap();
+    assert_eq!(client.get_cluster_config().unwrap(), cluster);
+    assert_eq!(*config.lock().unwrap(), cluster);
+
+    // Config of another cluster is rejected before sending.
+    cluster.set_id(2);
+    assert!(client.put_cluster_config(cluster).is_err());
+    assert_eq!(config.lock().unwrap().get_id(), 1);
+}
+
+#[test]
+fn test_rpc_client_builder() {
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            // Too slow to answer within the request timeout.
+            pdpb::CommandType::AllocId => {
+                thread::sleep(Duration::from_millis(500));
+                Some(new_alloc_id_resp(1, 1))
+            }
+            _ => None,
+        }
+    });
+
+    let client = pd.builder()
+        .request_timeout(Duration::from_millis(100))
+        .max_retry_count(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    let err = format!("{:?}", client.alloc_id().unwrap_err());
+    assert!(err.contains("after 2 retries"), err);
+}
+
+#[test]
+fn test_rpc_client_from_endpoints() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+
+    let endpoints: Vec<String> = members.iter().map(|pd| pd.endpoint()).collect();
+    let client = RpcClient::new_from_endpoints(endpoints.clone()).unwrap();
+    assert_eq!(client.cluster_id, 1);
+
+    // Duplicated endpoints are still rejected.
+    let endpoints = vec![endpoints[0].clone(), endpoints[0].clone()];
+    assert!(RpcClient::validate_endpoints(&endpoints).is_err());
+}
+
+#[test]
+fn test_rpc_client_endpoint_scheme() {
+    let pd = MockPd::new();
+    let urls = vec![pd.url()];
+    pd.set_handler(move |_| new_members_resp(1, &urls));
+
+    let client = RpcClient::new(&pd.url()).unwrap();
+    assert_eq!(client.cluster_id, 1);
+
+    assert!(RpcClient::new(&format!("https://{}", pd.endpoint())).is_err());
+    assert!(RpcClient::new(&format!("unix://{}", pd.endpoint())).is_err());
+}
+
+#[test]
+fn test_rpc_client_metrics_labels() {
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => Some(new_alloc_id_resp(1, 1)),
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    client.alloc_id().unwrap();
+
+    let mut labels = vec![];
+    for family in prometheus::gather() {
+        if family.get_name() != "tikv_pd_msg_send_duration_seconds" {
+            continue;
+        }
+        for metric in family.get_metric() {
+            for pair in metric.get_label() {
+                labels.push(pair.get_value().to_owned());
+            }
+        }
+    }
+    // Every request type has its own series.
+    assert!(labels.contains(&"get_pd_members".to_owned()));
+    assert!(labels.contains(&"alloc_id".to_owned()));
+}
+
+#[test]
+fn test_rpc_client_failure_counter() {
+    let pd = mock_with(move |cmd, _| Some(new_error_resp(1, cmd, "injected")));
+    let client = pd.builder()
+        .max_retry_count(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+
+    // Other tests may count failures at the same time, but never take any away.
+    let name = "tikv_pd_request_failure_total";
+    let header_errors = labeled_counter_value(name, "header_error");
+    assert!(client.alloc_id().is_err());
+    assert!(labeled_counter_value(name, "header_error") >= header_errors + 1.0);
+
+    pd.stop();
+    let rpc_errors = labeled_counter_value(name, "rpc_error");
+    assert!(client.alloc_id().is_err());
+    assert!(labeled_counter_value(name, "rpc_error") >= rpc_errors + 1.0);
+
+    // Failures are counted by a few kinds rather than by their messages.
+    for label in counter_labels(name) {
+        assert!(["header_error", "timeout", "rpc_error", "overload"].contains(&label.as_str()),
+                label);
+    }
+}
+
+#[test]
+fn test_rpc_client_cluster_id_mismatch() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    let pd = &members[0];
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+
+    // Another cluster is now served behind the same endpoint.
+    let urls = vec![pd.url()];
+    pd.set_handler(move |req| {
+        match req.get_cmd_type() {
+            pdpb::CommandType::GetPDMembers => new_members_resp(2, &urls),
+            pdpb::CommandType::AllocId => new_alloc_id_resp(2, 2),
+            cmd => new_error_resp(2, cmd, "unsupported"),
+        }
+    });
+    match client.alloc_id() {
+        Err(Error::ClusterIdChanged(1, 2)) => {}
+        other => panic!("expect cluster id changed error, got {:?}", other),
+    }
+
+    // The client never talks to PD again, even if the old cluster comes back.
+    setup_members(&members, 1);
+    let clone = client.clone();
+    for c in &[client, clone] {
+        match c.alloc_id() {
+            Err(Error::ClusterIdChanged(1, 2)) => {}
+            other => panic!("expect cluster id changed error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_rpc_client_keepalive() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+
+    for time in vec![Some(Duration::from_secs(1)), None] {
+        let client = members[0].builder()
+            .keepalive(time, Duration::from_secs(1))
+            .build()
+            .unwrap();
+        assert_eq!(client.alloc_id().unwrap(), 1);
+    }
+}
+
+#[test]
+fn test_rpc_client_health_check() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    let client = members[0].builder()
+        .max_retry_count(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    client.health_check().unwrap();
+
+    members[0].stop();
+    assert!(client.health_check().is_err());
+}
+
+#[test]
+fn test_rpc_client_update_endpoints() {
+    let old_members = vec![MockPd::new()];
+    setup_members(&old_members, 1);
+    let new_members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&new_members, 1);
+    let other_cluster = vec![MockPd::new()];
+    setup_members(&other_cluster, 2);
+
+    let client = RpcClient::new(&old_members[0].endpoint()).unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+
+    // A bad update keeps the old connection.
+    assert!(client.update_endpoints("").is_err());
+    assert!(client.update_endpoints(&other_cluster[0].endpoint()).is_err());
+    assert_eq!(client.current_endpoint(), Some(old_members[0].endpoint()));
+
+    let endpoints = format!("{},{}", new_members[1].endpoint(), new_members[0].endpoint());
+    client.update_endpoints(&endpoints).unwrap();
+    old_members[0].stop();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(client.current_endpoint(), Some(new_members[0].endpoint()));
+}
+
+#[test]
+fn test_rpc_client_clone() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RpcClient>();
+
+    let next_id = Arc::new(AtomicUsize::new(1));
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => {
+                Some(new_alloc_id_resp(1, next_id.fetch_add(1, Ordering::SeqCst) as u64))
+            }
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || (0..50).map(|_| client.alloc_id().unwrap()).collect::<Vec<_>>())
+        })
+        .collect();
+    let mut ids: Vec<u64> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 200);
+
+    // All clones share the connection.
+    let other = client.clone();
+    assert_eq!(other.current_endpoint(), Some(pd.endpoint()));
+}
+
+#[test]
+fn test_rpc_client_follow_leader() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let client = members[0].builder()
+        .max_retry_count(3)
+        .backoff(Duration::from_secs(1), Duration::from_secs(1))
+        .build()
+        .unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+
+    // The leader steps down and knows the new leader.
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = requests.clone();
+    let urls = vec![members[1].url(), members[0].url()];
+    members[0].handle_with(urls, move |cmd, _| {
+        requests2.fetch_add(1, Ordering::SeqCst);
+        Some(new_error_resp(1, cmd, "not leader"))
+    });
+
+    assert_eq!(client.alloc_id().unwrap(), 2);
+    // The request goes to the new leader rather than retrying the old one.
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert_eq!(client.current_endpoint(), Some(members[1].endpoint()));
+}
+
+#[test]
+fn test_rpc_client_retry_deadline() {
+    let pd = mock_with(move |cmd, _| Some(new_error_resp(1, cmd, "not leader")));
+
+    let client = pd.builder()
+        .max_retry_count(1000)
+        .retry_deadline(Duration::from_millis(300))
+        .backoff(Duration::from_millis(100), Duration::from_millis(100))
+        .build()
+        .unwrap();
+    match client.alloc_id() {
+        Err(Error::Timeout(msg)) => {
+            assert!(msg.contains("retried for"), "{}", msg);
+            assert!(msg.contains("not leader"), "{}", msg);
+        }
+        other => panic!("expect timeout error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_rpc_client_trace_id() {
+    let last_uuid = Arc::new(Mutex::new(vec![]));
+    let last_uuid2 = last_uuid.clone();
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            pdpb::CommandType::AllocId => {
+                *last_uuid2.lock().unwrap() = req.get_header().get_uuid().to_vec();
+                Some(new_alloc_id_resp(1, 1))
+            }
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    let id = Uuid::new_v4();
+    with_trace_id(id, || client.alloc_id()).unwrap();
+    assert_eq!(*last_uuid.lock().unwrap(), id.as_bytes().to_vec());
+}
+
+#[test]
+fn test_rpc_client_region_cache() {
+    let get_region_count = Arc::new(AtomicUsize::new(0));
+    let count = get_region_count.clone();
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::GetRegion => {
+                count.fetch_add(1, Ordering::SeqCst);
+                let mut region = metapb::Region::new();
+                region.set_id(2);
+                region.set_start_key(b"a".to_vec());
+                region.set_end_key(b"c".to_vec());
+                let mut resp = new_resp(1, pdpb::CommandType::GetRegion);
+                resp.mut_get_region().set_region(region);
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+
+    let client = pd.builder()
+        .region_cache_capacity(16)
+        .build()
+        .unwrap();
+    assert_eq!(client.get_region(b"b").unwrap().get_id(), 2);
+    assert_eq!(get_region_count.load(Ordering::SeqCst), 1);
+    // Hit.
+    assert_eq!(client.get_region(b"a").unwrap().get_id(), 2);
+    assert_eq!(client.get_region_by_id(2).unwrap().unwrap().get_id(), 2);
+    assert_eq!(get_region_count.load(Ordering::SeqCst), 1);
+    // Miss.
+    client.get_region(b"c").unwrap();
+    assert_eq!(get_region_count.load(Ordering::SeqCst), 2);
+
+    client.invalidate_region(2);
+    assert!(client.cached_region(b"b").is_none());
+    client.get_region(b"b").unwrap();
+    assert_eq!(get_region_count.load(Ordering::SeqCst), 3);
+
+    // A failed heartbeat evicts the region.
+    client.region_heartbeat(client.get_region(b"b").unwrap(),
+                          metapb::Peer::new(),
+                          vec![],
+                          vec![])
+        .unwrap_err();
+    assert!(client.cached_region_by_id(2).is_none());
+}
+
+#[test]
+fn test_rpc_client_validate_concurrently() {
+    let members = vec![MockPd::new(), MockPd::new(), MockPd::new(), MockPd::new()];
+    setup_members(&members[..1], 1);
+    // Two slow nodes which answer only after both are asked.
+    let arrived = Arc::new(AtomicUsize::new(0));
+    let all_arrived = Arc::new(AtomicBool::new(true));
+    for pd in &members[1..3] {
+        let urls = vec![members[0].url()];
+        let (arrived, all_arrived) = (arrived.clone(), all_arrived.clone());
+        pd.set_handler(move |req| {
+            if arrived.fetch_add(1, Ordering::SeqCst) < 2 && !wait_count(&arrived, 2) {
+                all_arrived.store(false, Ordering::SeqCst);
+            }
+            match req.get_cmd_type() {
+                pdpb::CommandType::GetPDMembers => new_members_resp(1, &urls),
+                cmd => new_error_resp(1, cmd, "unsupported"),
+            }
+        });
+    }
+    // And a dead one.
+    members[3].stop();
+
+    let endpoints: Vec<String> = members.iter().map(|pd| pd.endpoint()).collect();
+    let (cluster_id, _) = RpcClient::validate_endpoints(&endpoints).unwrap();
+    assert_eq!(cluster_id, 1);
+    assert_eq!(arrived.load(Ordering::SeqCst), 2);
+    assert!(all_arrived.load(Ordering::SeqCst));
+
+    let mut duplicated = endpoints.clone();
+    duplicated.push(members[0].url());
+    assert!(RpcClient::validate_endpoints(&duplicated).is_err());
+}
+
+#[test]
+fn test_rpc_client_close() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    let client = members[0].builder()
+        .backoff(Duration::from_secs(1), Duration::from_secs(1))
+        .build()
+        .unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+
+    // The request keeps retrying because PD is gone.
+    members[0].stop();
+    let client2 = client.clone();
+    let handle = thread::spawn(move || client2.alloc_id());
+    thread::sleep(Duration::from_millis(300));
+
+    client.close();
+    assert!(handle.join().unwrap().is_err());
+    assert_eq!(client.current_endpoint(), None);
+    assert!(client.alloc_id().is_err());
+}
+
+#[test]
+fn test_rpc_client_close_while_busy() {
+    let (arrived_tx, arrived_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel();
+    let (arrived_tx, release_rx) = (Mutex::new(arrived_tx), Mutex::new(release_rx));
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => {
+                arrived_tx.lock().unwrap().send(()).unwrap();
+                release_rx.lock().unwrap().recv().unwrap();
+                Some(new_alloc_id_resp(1, 1))
+            }
+            _ => None,
+        }
+    });
+    let client = pd.builder()
+        .request_timeout(Duration::from_secs(60))
+        .build()
+        .unwrap();
+
+    let client2 = client.clone();
+    let handle = thread::spawn(move || client2.alloc_id());
+    arrived_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    // The request is stalled in PD, closing doesn't wait for it.
+    let (closed_tx, closed_rx) = mpsc::channel();
+    let client2 = client.clone();
+    thread::spawn(move || {
+        client2.close();
+        closed_tx.send(()).unwrap();
+    });
+    closed_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    release_tx.send(()).unwrap();
+    // The request still gets its response, then the connection is dropped.
+    assert_eq!(handle.join().unwrap().unwrap(), 1);
+    assert_eq!(client.current_endpoint(), None);
+    assert!(client.alloc_id().is_err());
+}
+
+#[test]
+fn test_rpc_client_region_not_found() {
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            // PD answers an empty response if no region contains the key, or an
+            // empty region, only the region [a, b) with id 2 exists.
+            pdpb::CommandType::GetRegion => {
+                let mut resp = new_resp(1, pdpb::CommandType::GetRegion);
+                let key = req.get_get_region().get_region_key();
+                if key == b"a" {
+                    resp.mut_get_region().mut_region().set_id(2);
+                } else if key != b"k" {
+                    resp.mut_get_region().set_region(metapb::Region::new());
+                }
+                Some(resp)
+            }
+            pdpb::CommandType::GetRegionByID => {
+                let mut resp = new_resp(1, pdpb::CommandType::GetRegionByID);
+                match req.get_get_region_by_id().get_region_id() {
+                    2 => resp.mut_get_region_by_id().mut_region().set_id(2),
+                    3 => {}
+                    _ => resp.mut_get_region_by_id().set_region(metapb::Region::new()),
+                }
+                Some(resp)
+            }
+            _ => Some(new_error_resp(1, cmd, "cluster is not bootstrapped")),
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    assert_eq!(client.get_region(b"a").unwrap().get_id(), 2);
+    assert_eq!(client.get_region_by_id(2).unwrap().unwrap().get_id(), 2);
+    for key in &[b"k", b"z"] {
+        match client.get_region(*key) {
+            Err(Error::RegionNotFound(ref k)) if k == key => {}
+            other => panic!("expect region not found error, got {:?}", other),
+        }
+    }
+    assert!(client.get_region_by_id(3).unwrap().is_none());
+    assert!(client.get_region_by_id(4).unwrap().is_none());
+    match client.alloc_id() {
+        Err(Error::ClusterNotBootstrapped(1)) => {}
+        other => panic!("expect not bootstrapped error, got {:?}", other),
+    }
+}
+
+// Returns a PD that bootstraps with the first region it is sent, and the number of
+// bootstrap requests that took effect. If `lose_first` is set, the response to the
+// first bootstrap request comes after the client gives up on it.
+fn new_bootstrap_pd(lose_first: bool) -> (MockPd, Arc<AtomicUsize>) {
+    let bootstrapped = Arc::new(Mutex::new(None::<metapb::Region>));
+    let bootstrap_count = Arc::new(AtomicUsize::new(0));
+    let bootstrap_count2 = bootstrap_count.clone();
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            pdpb::CommandType::IsBootstrapped => {
+                let is_bootstrapped = bootstrapped.lock().unwrap().is_some();
+                let mut resp = new_resp(1, cmd);
+                resp.mut_is_bootstrapped().set_bootstrapped(is_bootstrapped);
+                Some(resp)
+            }
+            pdpb::CommandType::Bootstrap => {
+                let mut region = bootstrapped.lock().unwrap();
+                if region.is_some() {
+                    let mut resp = new_error_resp(1, cmd, "bootstrapped");
+                    resp.mut_header().mut_error().mut_bootstrapped();
+                    return Some(resp);
+                }
+                *region = Some(req.get_bootstrap().get_region().clone());
+                if bootstrap_count2.fetch_add(1, Ordering::SeqCst) == 0 && lose_first {
+                    thread::sleep(Duration::from_millis(500));
+                }
+                Some(new_resp(1, cmd))
+            }
+            pdpb::CommandType::GetRegionByID => {
+                let mut resp = new_resp(1, cmd);
+                if let Some(ref region) = *bootstrapped.lock().unwrap() {
+                    if region.get_id() == req.get_get_region_by_id().get_region_id() {
+                        resp.mut_get_region_by_id().set_region(region.clone());
+                    }
+                }
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+    (pd, bootstrap_count)
+}
+
+fn new_bootstrap_region(region_id: u64, store_id: u64) -> metapb::Region {
+    let mut region = metapb::Region::new();
+    region.set_id(region_id);
+    region.mut_region_epoch().set_conf_ver(1);
+    region.mut_region_epoch().set_version(1);
+    let mut peer = metapb::Peer::new();
+    peer.set_id(region_id + 1);
+    peer.set_store_id(store_id);
+    region.mut_peers().push(peer);
+    region
+}
+
+#[test]
+fn test_rpc_client_bootstrap_once() {
+    let (pd, bootstrap_count) = new_bootstrap_pd(true);
+    let client = pd.builder()
+        .request_timeout(Duration::from_millis(200))
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    let mut store = metapb::Store::new();
+    store.set_id(1);
+    client.bootstrap_cluster(store, new_bootstrap_region(2, 1)).unwrap();
+    assert_eq!(bootstrap_count.load(Ordering::SeqCst), 1);
+
+    // Another node tries to bootstrap the cluster again.
+    let mut store = metapb::Store::new();
+    store.set_id(4);
+    match client.bootstrap_cluster(store, new_bootstrap_region(5, 4)) {
+        Err(Error::ClusterBootstrapped(1)) => {}
+        other => panic!("expect bootstrapped error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_rpc_client_bootstrap_twice() {
+    let (pd, bootstrap_count) = new_bootstrap_pd(false);
+    let client = pd.builder().build().unwrap();
+    let mut store = metapb::Store::new();
+    store.set_id(1);
+    let region = new_bootstrap_region(2, 1);
+    client.bootstrap_cluster(store.clone(), region.clone()).unwrap();
+    // The node calls it again, e.g. after restarting before it knew the result, and
+    // must not clear the first region of the cluster.
+    client.bootstrap_cluster(store.clone(), region.clone()).unwrap();
+    assert_eq!(bootstrap_count.load(Ordering::SeqCst), 1);
+
+    // The same region with another epoch is not ours.
+    let mut stale = region.clone();
+    stale.mut_region_epoch().set_conf_ver(2);
+    match client.bootstrap_cluster(store, stale) {
+        Err(Error::ClusterBootstrapped(1)) => {}
+        other => panic!("expect bootstrapped error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_rpc_client_members() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let client = RpcClient::new(&members[0].endpoint()).unwrap();
+
+    let snapshot = client.get_members();
+    let names: Vec<&str> = snapshot.get_members().iter().map(|m| m.get_name()).collect();
+    assert_eq!(names, vec!["pd0", "pd1"]);
+    assert_eq!(snapshot.get_leader().get_name(), "pd0");
+
+    // The second member becomes the leader.
+    let urls = vec![members[1].url(), members[0].url()];
+    for pd in &members {
+        let urls = urls.clone();
+        pd.set_handler(move |_| new_members_resp(1, &urls));
+    }
+    client.reload_members().unwrap();
+    assert_eq!(client.get_members().get_leader().get_client_urls().to_vec(),
+               vec![members[1].url()]);
+    // The snapshot taken before is not changed.
+    assert_eq!(snapshot.get_leader().get_client_urls().to_vec(),
+               vec![members[0].url()]);
+
+    for pd in &members {
+        pd.stop();
+    }
+    assert!(client.reload_members().is_err());
+}
+
+#[test]
+fn test_rpc_client_retry_categories() {
+    let alloc_count = Arc::new(AtomicUsize::new(0));
+    let count = alloc_count.clone();
+    let error_msg = Arc::new(Mutex::new("not leader"));
+    let msg = error_msg.clone();
+    let pd = mock_with(move |cmd, _| {
+        count.fetch_add(1, Ordering::SeqCst);
+        Some(new_error_resp(1, cmd, *msg.lock().unwrap()))
+    });
+    let client = pd.builder()
+        .max_retry_count(3)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+
+    // A retriable header error is retried until the retries run out.
+    assert!(client.alloc_id().is_err());
+    assert_eq!(alloc_count.swap(0, Ordering::SeqCst), 3);
+
+    // Other header errors fail at once.
+    *error_msg.lock().unwrap() = "invalid request";
+    assert!(client.alloc_id().is_err());
+    assert_eq!(alloc_count.swap(0, Ordering::SeqCst), 1);
+
+    // Transport errors are retried and bounded too.
+    pd.stop();
+    let err = format!("{:?}", client.alloc_id().unwrap_err());
+    assert!(err.contains("after 3 retries"), err);
+    assert_eq!(alloc_count.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_rpc_client_connect_metrics() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let client = RpcClient::new(&members[1].endpoint()).unwrap();
+
+    // The leader can't be connected, then the other member is.
+    members[0].stop();
+    let failures = labeled_counter_value("tikv_pd_reconnect_total", "failure");
+    let successes = labeled_counter_value("tikv_pd_reconnect_total", "success");
+    let connects = histogram_sample_count("tikv_pd_connect_duration_seconds");
+    assert_eq!(client.alloc_id().unwrap(), 2);
+    assert!(labeled_counter_value("tikv_pd_reconnect_total", "failure") > failures);
+    assert!(labeled_counter_value("tikv_pd_reconnect_total", "success") > successes);
+    assert!(histogram_sample_count("tikv_pd_connect_duration_seconds") >= connects + 2);
+}
+
+#[test]
+fn test_rpc_client_skip_uninitialized() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members[1..], 1);
+    // The first PD is starting and has no cluster id yet.
+    let urls = vec![members[0].url()];
+    members[0].set_handler(move |req| {
+        match req.get_cmd_type() {
+            pdpb::CommandType::GetPDMembers => new_members_resp(0, &urls),
+            cmd => new_error_resp(0, cmd, "not ready"),
+        }
+    });
+
+    let endpoints: Vec<String> = members.iter().map(|pd| pd.endpoint()).collect();
+    let (cluster_id, _) = RpcClient::validate_endpoints(&endpoints).unwrap();
+    assert_eq!(cluster_id, 1);
+
+    // No PD is ready.
+    assert!(RpcClient::validate_endpoints(&endpoints[..1]).is_err());
+}
+
+#[test]
+fn test_rpc_client_leader_refresh() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let endpoints = format!("{},{}", members[0].endpoint(), members[1].endpoint());
+
+    // The old leader doesn't know it has stepped down, only the others
+    // know the new leader.
+    let urls = vec![members[0].url(), members[1].url()];
+    members[0].handle_with(urls, move |cmd, _| Some(new_error_resp(1, cmd, "not leader")));
+    let urls = vec![members[1].url(), members[0].url()];
+    members[1].handle_with(urls, move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => Some(new_alloc_id_resp(1, 2)),
+            _ => None,
+        }
+    });
+
+    let new_client = |threshold| {
+        RpcClientBuilder::new()
+            .endpoints(&endpoints)
+            .max_retry_count(4)
+            .backoff(Duration::from_millis(10), Duration::from_millis(10))
+            .leader_refresh_threshold(threshold)
+            .build()
+            .unwrap()
+    };
+
+    // Reconnecting keeps choosing the stale leader.
+    let client = new_client(0);
+    assert!(client.alloc_id().is_err());
+
+    let client = new_client(2);
+    assert_eq!(client.alloc_id().unwrap(), 2);
+    assert_eq!(client.current_endpoint(), Some(members[1].endpoint()));
+    assert_eq!(client.get_leader().unwrap().get_client_urls(), &[members[1].url()]);
+}
+
+#[test]
+fn test_rpc_client_connector() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+
+    // Fails the first two connections, then connects for real.
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let client = members[0].builder()
+        .max_retry_count(3)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .connector(move |ep, timeout| {
+            if connects2.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Err(box_err!("injected connect failure to {}", ep));
+            }
+            rpc_connect(ep, timeout)
+        })
+        .build()
+        .unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 3);
+
+    // The connection is reused.
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 3);
+
+    // Running out of retries returns the last connect error.
+    let client = members[0].builder()
+        .max_retry_count(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .connector(|ep, _| Err(box_err!("injected connect failure to {}", ep)))
+        .build()
+        .unwrap();
+    let err = format!("{:?}", client.alloc_id().unwrap_err());
+    assert!(err.contains("failed to connect"), err);
+}
+
+#[test]
+fn test_rpc_client_validate_failures() {
+    let pd = MockPd::new();
+    let urls = vec![pd.url()];
+    pd.set_handler(move |_| new_members_resp(0, &urls));
+    // Nothing listens on a freed port.
+    let dead = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("{}", listener.local_addr().unwrap())
+    };
+
+    let endpoints = vec![pd.endpoint(), dead.clone()];
+    let err = format!("{:?}", RpcClient::validate_endpoints(&endpoints).unwrap_err());
+    assert!(err.contains(&format!("{}: no cluster id", pd.endpoint())), err);
+    assert!(err.contains(&format!("{}: ", dead)), err);
+}
+
+#[test]
+fn test_rpc_client_validate_many() {
+    // More nodes than the threads which probe them, only the last one is alive.
+    let listeners: Vec<_> = (0..20).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+    let mut endpoints: Vec<String> =
+        listeners.iter().map(|l| format!("{}", l.local_addr().unwrap())).collect();
+    drop(listeners);
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    endpoints.push(members[0].endpoint());
+
+    let (cluster_id, _) = RpcClient::validate_endpoints(&endpoints).unwrap();
+    assert_eq!(cluster_id, 1);
+}
+
+#[test]
+fn test_rpc_client_warmup() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let endpoints = format!("{},{}", members[1].endpoint(), members[0].endpoint());
+
+    let new_client = |warmup| {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connects2 = connects.clone();
+        let client = RpcClientBuilder::new()
+            .endpoints(&endpoints)
+            .warmup(warmup)
+            .connector(move |ep, timeout| {
+                connects2.fetch_add(1, Ordering::SeqCst);
+                rpc_connect(ep, timeout)
+            })
+            .build()
+            .unwrap();
+        (client, connects)
+    };
+
+    let (client, connects) = new_client(false);
+    assert_eq!(client.current_endpoint(), None);
+    assert_eq!(connects.load(Ordering::SeqCst), 0);
+
+    // The first request is sent to the leader on the connection made in `build`.
+    let (client, connects) = new_client(true);
+    assert_eq!(client.current_endpoint(), Some(members[0].endpoint()));
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_rpc_client_connection_pool() {
+    let arrived = Arc::new(AtomicUsize::new(0));
+    let all_arrived = Arc::new(AtomicBool::new(true));
+    let (arrived2, all_arrived2) = (arrived.clone(), all_arrived.clone());
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => {
+                // The first requests are answered only after all of them arrive.
+                if arrived2.fetch_add(1, Ordering::SeqCst) < 4 && !wait_count(&arrived2, 4) {
+                    all_arrived2.store(false, Ordering::SeqCst);
+                }
+                Some(new_alloc_id_resp(1, 1))
+            }
+            _ => None,
+        }
+    });
+
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let client = pd.builder()
+        .connection_pool_size(4)
+        .connector(move |ep, timeout| {
+            connects2.fetch_add(1, Ordering::SeqCst);
+            rpc_connect(ep, timeout)
+        })
+        .build()
+        .unwrap();
+
+    // Concurrent requests don't wait for each other.
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || client.alloc_id().unwrap())
+        })
+        .collect();
+    for h in handles {
+        assert_eq!(h.join().unwrap(), 1);
+    }
+    assert_eq!(arrived.load(Ordering::SeqCst), 4);
+    assert!(all_arrived.load(Ordering::SeqCst));
+    assert_eq!(connects.load(Ordering::SeqCst), 4);
+
+    // The connections are reused.
+    client.alloc_id().unwrap();
+    assert_eq!(connects.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_rpc_client_switch_endpoint() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let endpoints = format!("{},{}", members[0].endpoint(), members[1].endpoint());
+    let client = RpcClientBuilder::new()
+        .endpoints(&endpoints)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(client.current_endpoint(), Some(members[0].endpoint()));
+
+    // The leader goes away, the client switches to the other node.
+    members[0].stop();
+    let leader_changes = counter_value("tikv_pd_leader_change_total");
+    assert_eq!(client.alloc_id().unwrap(), 2);
+    assert_eq!(client.current_endpoint(), Some(members[1].endpoint()));
+    assert!(counter_value("tikv_pd_leader_change_total") > leader_changes);
+}
+
+#[test]
+fn test_rpc_client_malformed_endpoints() {
+    // They are rejected by parsing, before any connection is tried.
+    for endpoints in &["", " ", ",,,", " , "] {
+        let err = format!("{:?}", RpcClient::new(endpoints).unwrap_err());
+        assert!(err.contains("no PD endpoints"), err);
+    }
+    for endpoints in &[":::", "http://", ":::,http://,localhost"] {
+        let err = format!("{:?}", RpcClient::new(endpoints).unwrap_err());
+        assert!(err.contains("all PD endpoints are malformed"), err);
+    }
+
+    // Valid endpoints are still used.
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    let client = RpcClient::new(&format!(":::,{}", members[0].endpoint())).unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+}
+
+#[test]
+fn test_rpc_client_wait_bootstrapped() {
+    let checks = Arc::new(AtomicUsize::new(0));
+    let checks2 = checks.clone();
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::IsBootstrapped => {
+                // Bootstrapped since the third check.
+                let bootstrapped = checks2.fetch_add(1, Ordering::SeqCst) >= 2;
+                let mut resp = new_resp(1, pdpb::CommandType::IsBootstrapped);
+                resp.mut_is_bootstrapped().set_bootstrapped(bootstrapped);
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    client.wait_bootstrapped(Duration::from_secs(5)).unwrap();
+    assert_eq!(checks.load(Ordering::SeqCst), 3);
+
+    pd.handle_with(vec![pd.url()], |cmd, _| {
+        match cmd {
+            pdpb::CommandType::IsBootstrapped => Some(new_resp(1, cmd)),
+            _ => None,
+        }
+    });
+    let start = Instant::now();
+    match client.wait_bootstrapped(Duration::from_millis(300)) {
+        Err(Error::Timeout(_)) => {}
+        other => panic!("expect timeout error, got {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn test_rpc_client_proxy() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+
+    let proxy = MockProxy::new("200 Connection established");
+    let client = members[0].builder()
+        .proxy(&proxy.endpoint())
+        .build()
+        .unwrap();
+    // Validating the endpoints goes through the proxy too.
+    assert_eq!(proxy.tunnels(), 1);
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(proxy.tunnels(), 2);
+    client.reload_members().unwrap();
+    assert_eq!(proxy.tunnels(), 3);
+
+    let proxy = MockProxy::new("403 Forbidden");
+    let res = members[0].builder()
+        .max_retry_count(1)
+        .proxy(&proxy.endpoint())
+        .build();
+    assert!(res.is_err());
+
+    let res = RpcClientBuilder::new().endpoints(&members[0].endpoint()).proxy(":::").build();
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_rpc_client_reconnect_policy() {
+    // The first AllocId is slower than the request timeout.
+    let slow = Arc::new(AtomicBool::new(true));
+    let slow2 = slow.clone();
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => {
+                if slow2.swap(false, Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                Some(new_alloc_id_resp(1, 1))
+            }
+            _ => None,
+        }
+    });
+
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let client = pd.builder()
+        .request_timeout(Duration::from_millis(200))
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .connector(move |ep, timeout| {
+            connects2.fetch_add(1, Ordering::SeqCst);
+            rpc_connect(ep, timeout)
+        })
+        .build()
+        .unwrap();
+    let mut req = pdpb::Request::new();
+    req.mut_header().set_cluster_id(1);
+    req.set_cmd_type(pdpb::CommandType::AllocId);
+    req.set_alloc_id(pdpb::AllocIdRequest::new());
+
+    // The timed out request is retried on the same connection.
+    let policy = ReconnectPolicy::AfterTimeouts(2);
+    let resp = client.send_with_policy("alloc_id", &req, policy).unwrap();
+    assert_eq!(resp.get_alloc_id().get_id(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+    // The late response to the retry is skipped.
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+    // The default policy reconnects at once.
+    slow.store(true, Ordering::SeqCst);
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_rpc_client_timeout_in_frame() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let client = members[0].builder()
+        .request_timeout(Duration::from_millis(200))
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .connector(move |ep, timeout| {
+            connects2.fetch_add(1, Ordering::SeqCst);
+            rpc_connect(ep, timeout)
+        })
+        .build()
+        .unwrap();
+    let mut req = pdpb::Request::new();
+    req.mut_header().set_cluster_id(1);
+    req.set_cmd_type(pdpb::CommandType::AllocId);
+    req.set_alloc_id(pdpb::AllocIdRequest::new());
+    let policy = ReconnectPolicy::AfterTimeouts(3);
+
+    // PD stalls after the header and a few bytes of the payload, the rest of the
+    // frame must not be read as the next response, so the stream is dropped.
+    members[0].stall_next_response(20, Duration::from_millis(400));
+    let resp = client.send_with_policy("alloc_id", &req, policy).unwrap();
+    assert_eq!(resp.get_alloc_id().get_id(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+    // PD stalls before the frame, the stream is reused, and the late response is
+    // skipped because the retry has another id.
+    members[0].stall_next_response(0, Duration::from_millis(400));
+    let resp = client.send_with_policy("alloc_id", &req, policy).unwrap();
+    assert_eq!(resp.get_alloc_id().get_id(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 2);
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(connects.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_rpc_client_get_region_and_leader() {
+    let has_leader = Arc::new(AtomicBool::new(true));
+    let has_leader2 = has_leader.clone();
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::GetRegion => {
+                let mut region = metapb::Region::new();
+                region.set_id(2);
+                let mut peer = metapb::Peer::new();
+                peer.set_id(3);
+                peer.set_store_id(4);
+                region.mut_peers().push(peer.clone());
+                let mut resp = new_resp(1, pdpb::CommandType::GetRegion);
+                resp.mut_get_region().set_region(region);
+                if has_leader2.load(Ordering::SeqCst) {
+                    resp.mut_get_region().set_leader(peer);
+                }
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    let (region, leader) = client.get_region_and_leader(b"a").unwrap();
+    assert_eq!(region.get_id(), 2);
+    let leader = leader.unwrap();
+    assert_eq!(leader.get_id(), 3);
+    assert_eq!(leader.get_store_id(), 4);
+
+    has_leader.store(false, Ordering::SeqCst);
+    let (region, leader) = client.get_region_and_leader(b"a").unwrap();
+    assert_eq!(region.get_id(), 2);
+    assert!(leader.is_none());
+    assert_eq!(client.get_region(b"a").unwrap().get_id(), 2);
+}
+
+#[test]
+fn test_rpc_client_reconnect_once() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let endpoints = format!("{},{}", members[0].endpoint(), members[1].endpoint());
+
+    // Counts connecting to the leader.
+    let leader = members[0].endpoint();
+    let leader_connects = Arc::new(AtomicUsize::new(0));
+    let leader_connects2 = leader_connects.clone();
+    let client = RpcClientBuilder::new()
+        .endpoints(&endpoints)
+        .connection_pool_size(4)
+        .warmup(true)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .connector(move |ep, timeout| {
+            if ep == leader {
+                leader_connects2.fetch_add(1, Ordering::SeqCst);
+            }
+            rpc_connect(ep, timeout)
+        })
+        .build()
+        .unwrap();
+    assert_eq!(leader_connects.load(Ordering::SeqCst), 4);
+
+    members[0].stop();
+    thread::sleep(Duration::from_millis(100));
+
+    // All connections fail at once, but only one of them tries the dead leader.
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || client.alloc_id().unwrap())
+        })
+        .collect();
+    for h in handles {
+        assert_eq!(h.join().unwrap(), 2);
+    }
+    assert_eq!(leader_connects.load(Ordering::SeqCst), 5);
+}
+
+#[test]
+fn test_rpc_client_with_deadline() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = requests.clone();
+    let pd = mock_with(move |cmd, _| {
+        requests2.fetch_add(1, Ordering::SeqCst);
+        Some(new_error_resp(1, cmd, "not leader"))
+    });
+    let client = pd.builder()
+        .max_retry_count(1000)
+        .backoff(Duration::from_millis(50), Duration::from_millis(50))
+        .build()
+        .unwrap();
+
+    // An expired deadline fails at once without sending anything.
+    let start = Instant::now();
+    match with_deadline(start, || client.alloc_id()) {
+        Err(Error::Timeout(_)) => {}
+        other => panic!("expect timeout error, got {:?}", other),
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+    // Retrying stops at the deadline, an inner deadline can't extend it.
+    let start = Instant::now();
+    let res = with_deadline(start + Duration::from_millis(300), || {
+        with_deadline(start + Duration::from_secs(10), || client.alloc_id())
+    });
+    match res {
+        Err(Error::Timeout(_)) => {}
+        other => panic!("expect timeout error, got {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    // It would take the 1000 retries 50 seconds.
+    let count = requests.load(Ordering::SeqCst);
+    assert!(count > 0 && count < 1000, "{}", count);
+}
+
+#[test]
+fn test_rpc_client_dry_run() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = requests.clone();
+    let pd = mock_with(move |cmd, _| {
+        requests2.fetch_add(1, Ordering::SeqCst);
+        Some(new_resp(1, cmd))
+    });
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+
+    let mut store = metapb::Store::new();
+    store.set_id(1);
+    store.set_address("127.0.0.1:20160".to_owned());
+    let req = client.dry_run_put_store(store.clone()).unwrap();
+    assert_eq!(req.get_cmd_type(), pdpb::CommandType::PutStore);
+    assert_eq!(req.get_header().get_cluster_id(), 1);
+    assert_eq!(req.get_put_store().get_store(), &store);
+
+    let mut region = metapb::Region::new();
+    region.set_id(2);
+    let req = client.dry_run_bootstrap_cluster(store.clone(), region.clone()).unwrap();
+    assert_eq!(req.get_cmd_type(), pdpb::CommandType::Bootstrap);
+    assert_eq!(req.get_bootstrap().get_region(), &region);
+
+    let mut cluster = metapb::Cluster::new();
+    cluster.set_id(1);
+    cluster.set_max_peer_count(5);
+    let req = client.dry_run_put_cluster_config(cluster.clone()).unwrap();
+    assert_eq!(req.get_put_cluster_config().get_cluster(), &cluster);
+
+    // Requests which PD would reject fail the same way as the real calls.
+    assert!(client.dry_run_put_store(metapb::Store::new()).is_err());
+    assert!(client.dry_run_bootstrap_cluster(store, metapb::Region::new()).is_err());
+    cluster.set_id(2);
+    assert!(client.dry_run_put_cluster_config(cluster).is_err());
+
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_rpc_client_try_alloc_id() {
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => {
+                let mut resp = new_resp(1, pdpb::CommandType::AllocId);
+                resp.mut_alloc_id().set_id(42);
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let client = pd.builder()
+        .backoff(Duration::from_secs(1), Duration::from_secs(1))
+        .connector(move |ep, timeout| {
+            connects2.fetch_add(1, Ordering::SeqCst);
+            rpc_connect(ep, timeout)
+        })
+        .build()
+        .unwrap();
+    assert_eq!(client.try_alloc_id().unwrap(), 42);
+
+    // The only attempt fails on the broken connection, it is not retried on a new one.
+    pd.stop();
+    connects.store(0, Ordering::SeqCst);
+    assert!(client.try_alloc_id().is_err());
+    assert_eq!(connects.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_rpc_client_get_region_with_epoch() {
+    // PD answers with version 1 for the first two requests, then version 2.
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = requests.clone();
+    let pd = mock_with(move |cmd, _| {
+        let new_region = || {
+            let version = if requests2.fetch_add(1, Ordering::SeqCst) < 2 { 1 } else { 2 };
+            let mut region = metapb::Region::new();
+            region.set_id(2);
+            region.mut_region_epoch().set_version(version);
+            region
+        };
+        match cmd {
+            pdpb::CommandType::GetRegion => {
+                let mut resp = new_resp(1, pdpb::CommandType::GetRegion);
+                resp.mut_get_region().set_region(new_region());
+                Some(resp)
+            }
+            pdpb::CommandType::GetRegionByID => {
+                let mut resp = new_resp(1, pdpb::CommandType::GetRegionByID);
+                resp.mut_get_region_by_id().set_region(new_region());
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+    let client = pd.builder()
+        .region_cache_capacity(10)
+        .build()
+        .unwrap();
+
+    // The stale region is cached.
+    assert_eq!(client.get_region(b"a").unwrap().get_region_epoch().get_version(), 1);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    // Both the cache and PD are stale at first, the client asks PD again.
+    let mut epoch = metapb::RegionEpoch::new();
+    epoch.set_version(2);
+    let region = client.get_region_with_epoch(b"a", &epoch).unwrap();
+    assert_eq!(region.get_region_epoch().get_version(), 2);
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    // The fresh region replaces the stale one in the cache.
+    assert_eq!(client.get_region(b"a").unwrap().get_region_epoch().get_version(), 2);
+    let region = client.get_region_by_id_with_epoch(2, &epoch).unwrap().unwrap();
+    assert_eq!(region.get_region_epoch().get_version(), 2);
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    // PD never catches up.
+    epoch.set_version(3);
+    match client.get_region_by_id_with_epoch(2, &epoch) {
+        Err(Error::StaleEpoch(2, ref got, ref want)) => {
+            assert_eq!(got.get_version(), 2);
+            assert_eq!(want.get_version(), 3);
+        }
+        other => panic!("expect stale epoch error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_rpc_client_cluster_config_cached() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = requests.clone();
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::GetClusterConfig => {
+                requests2.fetch_add(1, Ordering::SeqCst);
+                let mut resp = new_resp(1, pdpb::CommandType::GetClusterConfig);
+                resp.mut_get_cluster_config().mut_cluster().set_id(1);
+                resp.mut_get_cluster_config().mut_cluster().set_max_peer_count(3);
+                Some(resp)
+            }
+            pdpb::CommandType::PutClusterConfig => Some(new_resp(1, cmd)),
+            _ => None,
+        }
+    });
+    let client = pd.builder()
+        .cluster_config_ttl(Duration::from_millis(300))
+        .build()
+        .unwrap();
+
+    assert_eq!(client.cluster_config_cached().unwrap().get_max_peer_count(), 3);
+    assert_eq!(client.cluster_config_cached().unwrap().get_max_peer_count(), 3);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    // The uncached call always asks PD.
+    client.get_cluster_config().unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    thread::sleep(Duration::from_millis(400));
+    client.cluster_config_cached().unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    // Putting a config drops the cached one.
+    client.put_cluster_config(client.cluster_config_cached().unwrap()).unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    client.cluster_config_cached().unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_rpc_client_cluster_config_fetch_unlocked() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let (fetching_tx, fetching_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel();
+    let (fetching_tx, release_rx) = (Mutex::new(fetching_tx), Mutex::new(release_rx));
+    let requests2 = requests.clone();
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::GetClusterConfig => {
+                // The first fetch stalls until the test releases it.
+                if requests2.fetch_add(1, Ordering::SeqCst) == 0 {
+                    fetching_tx.lock().unwrap().send(()).unwrap();
+                    release_rx.lock().unwrap().recv().unwrap();
+                }
+                let mut resp = new_resp(1, cmd);
+                resp.mut_get_cluster_config().mut_cluster().set_id(1);
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+    // Long enough that the stalled fetch is never retried.
+    let client = pd.builder()
+        .request_timeout(Duration::from_secs(60))
+        .build()
+        .unwrap();
+
+    let client2 = client.clone();
+    let handle = thread::spawn(move || client2.cluster_config_cached().unwrap());
+    fetching_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    // Invalidating doesn't wait for the stalled fetch.
+    let (done_tx, done_rx) = mpsc::channel();
+    let client2 = client.clone();
+    thread::spawn(move || {
+        client2.invalidate_cluster_config();
+        done_tx.send(()).unwrap();
+    });
+    done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    release_tx.send(()).unwrap();
+    handle.join().unwrap();
+
+    // The config fetched before the invalidation is not cached.
+    client.cluster_config_cached().unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_rpc_client_retry_metrics() {
+    let failures = Arc::new(AtomicUsize::new(2));
+    let failures2 = failures.clone();
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AskSplit => {
+                if failures2.load(Ordering::SeqCst) > 0 {
+                    failures2.fetch_sub(1, Ordering::SeqCst);
+                    return Some(new_error_resp(1, pdpb::CommandType::AskSplit, "not leader"));
+                }
+                Some(new_resp(1, pdpb::CommandType::AskSplit))
+            }
+            _ => None,
+        }
+    });
+    let client = pd.builder()
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+
+    // No other test sends ask_split, so all its retries come from here.
+    let retries = labeled_counter_value("tikv_pd_request_retry_total", "ask_split");
+    client.ask_split(metapb::Region::new()).unwrap();
+    assert_eq!(labeled_counter_value("tikv_pd_request_retry_total", "ask_split"),
+               retries + 2.0);
+}
+
+#[test]
+fn test_rpc_client_get_regions_by_ids() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = requests.clone();
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            pdpb::CommandType::GetRegionByID => {
+                // The first request hits a PD which is not leader.
+                if requests2.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Some(new_error_resp(1, pdpb::CommandType::GetRegionByID, "not leader"));
+                }
+                let id = req.get_get_region_by_id().get_region_id();
+                let mut resp = new_resp(1, pdpb::CommandType::GetRegionByID);
+                // Only regions with odd ids exist.
+                if id % 2 == 1 {
+                    resp.mut_get_region_by_id().mut_region().set_id(id);
+                }
+                Some(resp)
+            }
+            _ => None,
+        }
+    });
+    let client = pd.builder()
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .build()
+        .unwrap();
+
+    let ids: Vec<u64> = (1..40).rev().collect();
+    let regions = client.get_regions_by_ids(&ids).unwrap();
+    assert_eq!(regions.len(), ids.len());
+    for (&id, region) in ids.iter().zip(&regions) {
+        match *region {
+            Some(ref region) => assert_eq!(region.get_id(), id),
+            None => assert_eq!(id % 2, 0),
+        }
+    }
+    assert_eq!(regions.iter().filter(|r| r.is_some()).count(), 20);
+    // The failed request is sent again.
+    assert_eq!(requests.load(Ordering::SeqCst), ids.len() + 1);
+    assert!(client.get_regions_by_ids(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_rpc_client_idle_ping() {
+    let pd = MockPd::new();
+    let urls = vec![pd.url()];
+    let pings = Arc::new(AtomicUsize::new(0));
+    let pings2 = pings.clone();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    pd.set_handler(move |req| {
+        match req.get_cmd_type() {
+            pdpb::CommandType::GetPDMembers => {
+                // Validating endpoints sends no cluster id.
+                if req.get_header().get_cluster_id() == 1 {
+                    pings2.fetch_add(1, Ordering::SeqCst);
+                    let _ = tx.lock().unwrap().send(());
+                }
+                new_members_resp(1, &urls)
+            }
+            cmd => new_error_resp(1, cmd, "unsupported"),
+        }
+    });
+    let client = pd.builder()
+        .idle_ping_interval(Duration::from_millis(100))
+        .build()
+        .unwrap();
+
+    // The idle connection keeps being pinged without any request.
+    for _ in 0..3 {
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    // No ping is sent after the client is closed.
+    client.close();
+    thread::sleep(Duration::from_millis(150));
+    let count = pings.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(pings.load(Ordering::SeqCst), count);
+
+    // Pinging is disabled by default.
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+    let count = pings.load(Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(pings.load(Ordering::SeqCst), count);
+    client.close();
+}
+
+#[test]
+fn test_rpc_client_down_peers() {
+    let down_peers = Arc::new(Mutex::new(vec![]));
+    let down_peers2 = down_peers.clone();
+    let pd = mock_with(move |cmd, req| {
+        match cmd {
+            pdpb::CommandType::RegionHeartbeat => {
+                *down_peers2.lock().unwrap() =
+                    req.get_region_heartbeat().get_down_peers().to_vec();
+                Some(new_resp(1, pdpb::CommandType::RegionHeartbeat))
+            }
+            _ => None,
+        }
+    });
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+
+    let mut peer = metapb::Peer::new();
+    peer.set_id(3);
+    peer.set_store_id(4);
+    let stats = new_peer_stats(peer.clone(), Duration::from_secs(60));
+    client.region_heartbeat(metapb::Region::new(), metapb::Peer::new(), vec![stats], vec![])
+        .unwrap();
+    let down_peers = down_peers.lock().unwrap();
+    assert_eq!(down_peers.len(), 1);
+    assert_eq!(down_peers[0].get_peer().get_id(), 3);
+    assert_eq!(down_peers[0].get_down_seconds(), 60);
+}
+
+#[test]
+fn test_rpc_client_with_cancel() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = requests.clone();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let pd = mock_with(move |cmd, _| {
+        requests2.fetch_add(1, Ordering::SeqCst);
+        let _ = tx.lock().unwrap().send(());
+        Some(new_error_resp(1, cmd, "not leader"))
+    });
+    // Long enough that the request never retries within the test.
+    let client = pd.builder()
+        .backoff(Duration::from_secs(60), Duration::from_secs(60))
+        .build()
+        .unwrap();
+
+    // A cancelled token fails the request without sending it.
+    let token = Arc::new(AtomicBool::new(true));
+    match with_cancel(token, || client.alloc_id()) {
+        Err(Error::Cancelled) => {}
+        other => panic!("expect cancelled error, got {:?}", other),
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+    // Cancel the request while it is waiting to retry.
+    let token = Arc::new(AtomicBool::new(false));
+    let token2 = token.clone();
+    let (res_tx, res_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let outer = Arc::new(AtomicBool::new(false));
+        let res = with_cancel(outer, || with_cancel(token2, || client.alloc_id()));
+        res_tx.send(res).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    token.store(true, Ordering::SeqCst);
+    match res_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+        Err(Error::Cancelled) => {}
+        other => panic!("expect cancelled error, got {:?}", other),
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_rpc_client_debug() {
+    let pd = MockPd::new();
+    // A large cluster, the other members are never connected.
+    let mut urls = vec![pd.url()];
+    for i in 0..100 {
+        urls.push(format!("http://127.0.0.1:{}", 10000 + i));
+    }
+    pd.handle_with(urls, |_, _| None);
+    let client = RpcClient::new(&pd.endpoint()).unwrap();
+
+    let summary = format!("{:?}", client);
+    assert!(summary.len() < 200, "{}", summary);
+    assert!(summary.contains("cluster_id: 1"), "{}", summary);
+    assert!(summary.contains("members: 101"), "{}", summary);
+    assert!(summary.contains(&pd.url()), "{}", summary);
+    assert!(!summary.contains("127.0.0.1:10099"), "{}", summary);
+
+    // The alternate form prints all members.
+    assert!(format!("{:#?}", client).contains("127.0.0.1:10099"));
+}
+
+#[test]
+fn test_rpc_client_low_latency_strategy() {
+    let members = vec![MockPd::new(), MockPd::new(), MockPd::new()];
+    let urls: Vec<_> = members.iter().map(|pd| pd.url()).collect();
+    // The members know no leader, the second one answers fastest.
+    for (pd, delay) in members.iter().zip(vec![200, 0, 400]) {
+        let urls = urls.clone();
+        pd.set_handler(move |req| {
+            match req.get_cmd_type() {
+                pdpb::CommandType::GetPDMembers => {
+                    thread::sleep(Duration::from_millis(delay));
+                    let mut resp = new_members_resp(1, &urls);
+                    resp.mut_get_pd_members().clear_leader();
+                    resp
+                }
+                pdpb::CommandType::AllocId => new_resp(1, pdpb::CommandType::AllocId),
+                cmd => new_error_resp(1, cmd, "unsupported"),
+            }
+        });
+    }
+    let endpoints: Vec<_> = members.iter().map(|pd| pd.endpoint()).collect();
+
+    for _ in 0..3 {
+        let client = RpcClientBuilder::new()
+            .endpoint_list(&endpoints)
+            .endpoint_strategy(EndpointStrategy::LowLatency)
+            .build()
+            .unwrap();
+        client.alloc_id().unwrap();
+        assert_eq!(client.current_endpoint().unwrap(), members[1].endpoint());
+    }
+}
+
+#[test]
+fn test_rpc_client_single_pd_unavailable() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    let connects = Arc::new(AtomicUsize::new(0));
+    let connects2 = connects.clone();
+    let client = members[0].builder()
+        .max_retry_count(1000)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .single_pd_retry_count(3)
+        .connector(move |ep, timeout| {
+            connects2.fetch_add(1, Ordering::SeqCst);
+            rpc_connect(ep, timeout)
+        })
+        .build()
+        .unwrap();
+    assert_eq!(client.alloc_id().unwrap(), 1);
+
+    members[0].stop();
+    connects.store(0, Ordering::SeqCst);
+    match client.alloc_id() {
+        Err(Error::SinglePdUnavailable(ref ep)) => assert_eq!(*ep, members[0].endpoint()),
+        other => panic!("expect single PD unavailable error, got {:?}", other),
+    }
+    // It gives up after 3 failed connects rather than 1000 retries.
+    assert_eq!(connects.load(Ordering::SeqCst), 3);
+
+    // A cluster with more nodes keeps retrying the others.
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let client = members[0].builder()
+        .max_retry_count(5)
+        .backoff(Duration::from_millis(10), Duration::from_millis(10))
+        .single_pd_retry_count(1)
+        .build()
+        .unwrap();
+    for pd in &members {
+        pd.stop();
+    }
+    match client.alloc_id() {
+        Err(Error::SinglePdUnavailable(_)) => panic!("the cluster has two nodes"),
+        Err(_) => {}
+        Ok(id) => panic!("expect error, got {}", id),
+    }
+}
+
+#[test]
+fn test_rpc_client_log_context() {
+    let members = vec![MockPd::new()];
+    setup_members(&members, 1);
+    let client = RpcClient::new(&members[0].endpoint()).unwrap();
+    assert_eq!(client.log_tag(), "[pd cluster_id=1]");
+
+    let client = members[0].builder()
+        .log_context("pd_endpoint", &members[0].endpoint())
+        .log_context("subsystem", "gc")
+        .build()
+        .unwrap();
+    assert_eq!(client.log_tag(),
+               format!("[pd cluster_id=1 pd_endpoint={} subsystem=gc]",
+                       members[0].endpoint()));
+}
+
+#[test]
+fn test_rpc_client_max_inflight_requests() {
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let (running2, max_running2) = (running.clone(), max_running.clone());
+    let pd = mock_with(move |cmd, _| {
+        match cmd {
+            pdpb::CommandType::AllocId => {
+                let n = running2.fetch_add(1, Ordering::SeqCst) + 1;
+                if n > max_running2.load(Ordering::SeqCst) {
+                    max_running2.store(n, Ordering::SeqCst);
+                }
+                thread::sleep(Duration::from_millis(300));
+                running2.fetch_sub(1, Ordering::SeqCst);
+                Some(new_alloc_id_resp(1, 1))
+            }
+            _ => None,
+        }
+    });
+
+    // The second request fails at once while the first one is in flight.
+    let client = pd.builder()
+        .connection_pool_size(2)
+        .max_inflight_requests(1, OverloadPolicy::FailFast)
+        .build()
+        .unwrap();
+    let client2 = client.clone();
+    let handle = thread::spawn(move || client2.alloc_id().unwrap());
+    thread::sleep(Duration::from_millis(100));
+    match client.alloc_id() {
+        Err(Error::TooManyRequests(1)) => {}
+        other => panic!("expect too many requests error, got {:?}", other),
+    }
+    assert_eq!(handle.join().unwrap(), 1);
+    assert_eq!(client.alloc_id().unwrap(), 1);
+
+    // Or waits for it, though there are enough connections.
+    let client = pd.builder()
+        .connection_pool_size(2)
+        .max_inflight_requests(1, OverloadPolicy::Queue)
+        .build()
+        .unwrap();
+    max_running.store(0, Ordering::SeqCst);
+    let handles: Vec<_> = (0..2)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || client.alloc_id().unwrap())
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), 1);
+    }
+    assert_eq!(max_running.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_rpc_client_leader_hint() {
+    let members = vec![MockPd::new(), MockPd::new(), MockPd::new()];
+    let urls: Vec<_> = members.iter().map(|pd| pd.url()).collect();
+    // The members know no leader, so the client connects to any of them.
+    for pd in &members {
+        let urls = urls.clone();
+        pd.set_handler(move |req| {
+            match req.get_cmd_type() {
+                pdpb::CommandType::GetPDMembers => {
+                    let mut resp = new_members_resp(1, &urls);
+                    resp.mut_get_pd_members().clear_leader();
+                    resp
+                }
+                pdpb::CommandType::AllocId => new_alloc_id_resp(1, 1),
+                cmd => new_error_resp(1, cmd, "unsupported"),
+            }
+        });
+    }
+    let endpoints: Vec<_> = members.iter().map(|pd| pd.endpoint()).collect();
+    let new_client = |hint: &str, connects: Arc<Mutex<Vec<String>>>| {
+        RpcClientBuilder::new()
+            .endpoint_list(&endpoints)
+            .leader_hint(hint)
+            .connector(move |ep, timeout| {
+                connects.lock().unwrap().push(ep.to_owned());
+                rpc_connect(ep, timeout)
+            })
+            .build()
+            .unwrap()
+    };
+
+    for _ in 0..3 {
+        let connects = Arc::new(Mutex::new(vec![]));
+        let client = new_client(&urls[2], connects.clone());
+        client.alloc_id().unwrap();
+        assert_eq!(*connects.lock().unwrap(), vec![members[2].endpoint()]);
+    }
+
+    // A stale leader which is not a member any more is never connected.
+    let connects = Arc::new(Mutex::new(vec![]));
+    let client = new_client("127.0.0.1:1", connects.clone());
+    client.alloc_id().unwrap();
+    assert!(!connects.lock().unwrap().contains(&"127.0.0.1:1".to_owned()));
+}
+
+#[test]
+fn test_rpc_client_on_leader_change() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let new_client = |hint: Option<String>, leaders: Arc<Mutex<Vec<String>>>| {
+        let mut builder = members[1].builder()
+            .on_leader_change(move |ep| leaders.lock().unwrap().push(ep.to_owned()));
+        if let Some(hint) = hint {
+            builder = builder.leader_hint(&hint);
+        }
+        builder.build().unwrap()
+    };
+
+    let leaders = Arc::new(Mutex::new(vec![]));
+    let client = new_client(None, leaders.clone());
+    client.alloc_id().unwrap();
+    assert_eq!(*leaders.lock().unwrap(), vec![members[0].endpoint()]);
+
+    // Restarts with the persisted leader, which is still the leader.
+    let persisted = leaders.lock().unwrap()[0].clone();
+    let leaders = Arc::new(Mutex::new(vec![]));
+    let client = new_client(Some(persisted), leaders.clone());
+    assert_eq!(client.alloc_id().unwrap(), 1);
+    assert_eq!(client.current_endpoint().unwrap(), members[0].endpoint());
+    assert!(leaders.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_rpc_client_on_leader_change_uses_client() {
+    let members = vec![MockPd::new(), MockPd::new()];
+    setup_members(&members, 1);
+    let slot: Arc<Mutex<Option<RpcClient>>> = Arc::new(Mutex::new(None));
+    let (tx, rx) = mpsc::channel();
+    let hook_slot = slot.clone();
+    let tx = Mutex::new(tx);
+    let client = members[1].builder()
+        .on_leader_change(move |_| {
+            // The client is usable in the hook, no lock of it is held.
+            let client = hook_slot.lock().unwrap().clone().unwrap();
+            let _ = tx.lock().unwrap().send(client.current_endpoint());
+        })
+        .build()
+        .unwrap();
+    *slot.lock().unwrap() = Some(client.clone());
+
+    thread::spawn(move || client.alloc_id().unwrap());
+    let ep = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(ep, Some(members[0].endpoint()));
+}
+
+#[test]
+fn test_rpc_client_endpoint_strategy() {
+    // Every member reports itself as the leader.
+    let members = vec![MockPd::new(), MockPd::new(), MockPd::new()];
+    for (i, pd) in members.iter().enumerate() {
+        let mut urls: Vec<_> = members.iter().map(|pd| pd.url()).collect();
+        urls.swap(0, i);
+        pd.handle_with(urls, move |cmd, _| {
+            match cmd {
+                pdpb::CommandType::AllocId => Some(new_alloc_id_resp(1, i as u64 + 1)),
+                _ => None,
+            }
+        });
+    }
+    let mut endpoints: Vec<_> = members.iter().map(|pd| pd.endpoint()).collect();
+
+    // The members are taken from the first endpoint.
+    let (_, resp) = RpcClient::validate_endpoints(&endpoints).unwrap();
+    assert_eq!(resp.get_leader().get_client_urls(), &[members[0].url()]);
+    endpoints.reverse();
+    let (_, resp) = RpcClient::validate_endpoints(&endpoints).unwrap();
+    assert_eq!(resp.get_leader().get_client_urls(), &[members[2].url()]);
+
+    // So the client connects to the leader reported by the first endpoint.
+    for _ in 0..3 {
+        let client = RpcClientBuilder::new()
+            .endpoint_list(&endpoints)
+            .endpoint_strategy(EndpointStrategy::InOrder)
+            .build()
+            .unwrap();
+        assert_eq!(client.alloc_id().unwrap(), 3);
+    }
+
+    // Without a leader, the nodes are connected in the given order.
+    for pd in &members {
+        let urls: Vec<_> = members.iter().map(|pd| pd.url()).collect();
+        pd.set_handler(move |req| {
+            match req.get_cmd_type() {
+                pdpb::CommandType::GetPDMembers => {
+                    let mut resp = new_members_resp(1, &urls);
+                    resp.mut_get_pd_members().clear_leader();
+                    resp
+                }
+                pdpb::CommandType::AllocId => new_alloc_id_resp(1, 1),
+                cmd => new_error_resp(1, cmd, "unsupported"),
+            }
+        });
+    }
+    members[2].stop();
+    for _ in 0..3 {
+        let connects = Arc::new(Mutex::new(vec![]));
+        let connects2 = connects.clone();
+        let client = RpcClientBuilder::new()
+            .endpoint_list(&endpoints)
+            .endpoint_strategy(EndpointStrategy::InOrder)
+            .connector(move |ep, timeout| {
+                connects2.lock().unwrap().push(ep.to_owned());
+                rpc_connect(ep, timeout)
+            })
+            .build()
+            .unwrap();
+        client.alloc_id().unwrap();
+        assert_eq!(*connects.lock().unwrap(), endpoints[..2].to_vec());
+    }
+}
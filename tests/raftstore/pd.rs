@@ -24,7 +24,7 @@ use kvproto::eraftpb;
 use tikv::pd::{PdClient, Result, Error, Key};
 use tikv::raftstore::store::keys::{enc_end_key, enc_start_key, data_key};
 use tikv::raftstore::store::util::check_key_in_region;
-use tikv::util::{HandyRwLock, escape};
+use tikv::util::HandyRwLock;
 use super::util::*;
 
 // Rule is just for special test which we want do more accurate control
@@ -47,6 +47,7 @@ struct Cluster {
     regions: BTreeMap<Key, metapb::Region>,
     region_id_keys: HashMap<u64, Key>,
     base_id: AtomicUsize,
+    tso: AtomicUsize,
     rule: Option<Rule>,
 
     store_stats: HashMap<u64, pdpb::StoreStats>,
@@ -68,6 +69,7 @@ impl Cluster {
             regions: BTreeMap::new(),
             region_id_keys: HashMap::new(),
             base_id: AtomicUsize::new(1000),
+            tso: AtomicUsize::new(1),
             rule: None,
             store_stats: HashMap::new(),
             split_count: 0,
@@ -100,6 +102,10 @@ impl Cluster {
         Ok(self.base_id.fetch_add(1, Ordering::Relaxed) as u64)
     }
 
+    fn get_tso(&self) -> Result<u64> {
+        Ok(self.tso.fetch_add(1, Ordering::Relaxed) as u64)
+    }
+
     fn put_store(&mut self, store: metapb::Store) -> Result<()> {
         let mut s = self.stores.entry(store.get_id()).or_insert_with(Store::default);
         s.store = store;
@@ -514,6 +520,10 @@ impl PdClient for TestPdClient {
         self.cluster.rl().alloc_id()
     }
 
+    fn get_tso(&self) -> Result<u64> {
+        self.cluster.rl().get_tso()
+    }
+
     fn put_store(&self, store: metapb::Store) -> Result<()> {
         try!(self.check_bootstrap());
         self.cluster.wl().put_store(store)
@@ -533,7 +543,7 @@ impl PdClient for TestPdClient {
             }
         }
 
-        Err(box_err!("no region contains key {:?}", escape(key)))
+        Err(Error::RegionNotFound(key.to_vec()))
     }
 
     fn get_region_by_id(&self, region_id: u64) -> Result<Option<metapb::Region>> {
@@ -546,6 +556,15 @@ impl PdClient for TestPdClient {
         Ok(self.cluster.rl().meta.clone())
     }
 
+    fn put_cluster_config(&self, config: metapb::Cluster) -> Result<()> {
+        try!(self.check_bootstrap());
+        if config.get_id() != self.cluster_id {
+            return Err(box_err!("invalid cluster id {}", config.get_id()));
+        }
+        self.cluster.wl().meta = config;
+        Ok(())
+    }
+
 
     fn region_heartbeat(&self,
                         region: metapb::Region,
@@ -575,14 +594,14 @@ impl PdClient for TestPdClient {
         Ok(resp)
     }
 
-    fn store_heartbeat(&self, stats: pdpb::StoreStats) -> Result<()> {
+    fn store_heartbeat(&self, stats: pdpb::StoreStats) -> Result<pdpb::StoreHeartbeatResponse> {
         try!(self.check_bootstrap());
 
         // Cache it directly now.
         let store_id = stats.get_store_id();
         self.cluster.wl().store_stats.insert(store_id, stats);
 
-        Ok(())
+        Ok(pdpb::StoreHeartbeatResponse::new())
     }
 
     fn report_split(&self, _: metapb::Region, _: metapb::Region) -> Result<()> {
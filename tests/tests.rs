@@ -38,6 +38,7 @@ extern crate tipb;
 extern crate time;
 extern crate rustc_serialize;
 extern crate fnv;
+extern crate prometheus;
 extern crate test;
 
 mod raft;